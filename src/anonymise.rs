@@ -0,0 +1,160 @@
+//! Pseudonymising and redacting logs before they're shared.
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::BufReader;
+use std::path::Path;
+use regex::{Captures, Regex, RegexBuilder};
+use crate::error::Error;
+use crate::fchat_index::FChatIndex;
+use crate::fchat_message::{FChatMessage, STRUCTURAL_READ};
+use crate::names::canonical_name;
+use crate::profile::FChatConversationKind;
+use crate::{FChatMessageReader, FChatWriter, FChatWriterOptions, FChatWriterOrdering};
+
+/// Replaces every character name with a pseudonym, the same one each time the name comes up, and blanks out text
+/// matching any of its redactions. Names are matched case-insensitively, like F-Chat does.
+pub struct FChatAnonymiser {
+    /// Pseudonyms are this followed by a number, e.g. "Person 1".
+    pub prefix: String,
+    /// What redacted text is replaced with.
+    pub redaction: String,
+    redactions: Vec<Regex>,
+    /// Lowercased name to pseudonym.
+    pseudonyms: BTreeMap<String, String>,
+    /// `[user]` and `[icon]` tags, with the name in the second group.
+    name_tags: Regex,
+    /// Matches any known name as a whole word. Rebuilt when a name is added.
+    mentions: Option<Regex>,
+}
+
+impl Default for FChatAnonymiser {
+    fn default() -> Self {
+        FChatAnonymiser {
+            prefix: String::from("Person"),
+            redaction: String::from("[redacted]"),
+            redactions: Vec::new(),
+            pseudonyms: BTreeMap::new(),
+            name_tags: Regex::new(r"(?i)(\[(?:user|icon)\])([^\[\]]+)(\[/(?:user|icon)\])").expect("the pattern is valid"),
+            mentions: None,
+        }
+    }
+}
+
+impl FChatAnonymiser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also replace anything `pattern` matches in message text with the [redaction](#structfield.redaction).
+    pub fn with_redaction(mut self, pattern: Regex) -> Self {
+        self.redactions.push(pattern);
+        self
+    }
+
+    /// The pseudonym for `name`, handing out the next one if it hasn't been seen before.
+    pub fn pseudonym(&mut self, name: &str) -> String {
+        let key = canonical_name(name);
+        if let Some(pseudonym) = self.pseudonyms.get(&key) {
+            return pseudonym.clone();
+        }
+        let pseudonym = format!("{} {}", self.prefix, self.pseudonyms.len() + 1);
+        self.pseudonyms.insert(key, pseudonym.clone());
+        self.mentions = None;
+        pseudonym
+    }
+
+    /// Lowercased names and the pseudonyms they were given.
+    pub fn pseudonyms(&self) -> &BTreeMap<String, String> {
+        &self.pseudonyms
+    }
+
+    /// Give the sender and anyone tagged in `message` a pseudonym, without changing it. Learning every message before
+    /// anonymising any means names mentioned before they first speak are still replaced.
+    pub fn learn(&mut self, message: &FChatMessage) {
+        self.pseudonym(&message.sender);
+        let names: Vec<String> = self.name_tags.captures_iter(message.body.text())
+            .map(|captures| captures[2].to_string())
+            .collect();
+        for name in names {
+            self.pseudonym(&name);
+        }
+    }
+
+    /// A copy of `message` with its sender, `[user]` and `[icon]` tags, and mentions of known names replaced by
+    /// pseudonyms, and redactions applied.
+    pub fn anonymise(&mut self, message: &FChatMessage) -> FChatMessage {
+        self.learn(message);
+        self.build_mentions();
+        let mut text = message.body.text().to_string();
+        for pattern in &self.redactions {
+            text = pattern.replace_all(&text, self.redaction.as_str()).into_owned();
+        }
+        let pseudonyms = &self.pseudonyms;
+        let lookup = |name: &str| pseudonyms.get(&canonical_name(name)).cloned().unwrap_or_else(|| name.to_string());
+        text = self.name_tags.replace_all(&text, |captures: &Captures| {
+            format!("{}{}{}", &captures[1], lookup(&captures[2]), &captures[3])
+        }).into_owned();
+        if let Some(mentions) = &self.mentions {
+            text = mentions.replace_all(&text, |captures: &Captures| lookup(&captures[0])).into_owned();
+        }
+        FChatMessage {
+            datetime: message.datetime,
+            sender: self.pseudonym(&message.sender),
+            body: message.body.with_text(text),
+        }
+    }
+
+    fn build_mentions(&mut self) {
+        if self.mentions.is_none() && !self.pseudonyms.is_empty() {
+            let mut names: Vec<&String> = self.pseudonyms.keys().collect();
+            // Longer names first, so "Alice Smith" is replaced whole rather than as "Alice".
+            names.sort_by_key(|name| std::cmp::Reverse(name.len()));
+            let alternatives: Vec<String> = names.iter().map(|name| regex::escape(name)).collect();
+            let pattern = format!(r"(?:^|\b)(?:{})(?:\b|$)", alternatives.join("|"));
+            self.mentions = Some(RegexBuilder::new(&pattern).case_insensitive(true).build().expect("names are escaped"));
+        }
+    }
+
+    /// Write an anonymised copy of the log and idx at `log_path` and `idx_path` to `out_log_path` and `out_idx_path`,
+    /// replacing anything already there. For a private conversation the copy's idx header gets the pseudonym of the
+    /// original's, while channels and the console keep their name, so it isn't taken for a character's in the text.
+    /// Text that isn't valid UTF-8 is decoded lossily, as old logs have some. Returns how many messages were written.
+    pub fn anonymise_files<P, Q, R, S>(&mut self, log_path: P, idx_path: Q, out_log_path: R, out_idx_path: S) -> Result<u64, Error>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+        R: AsRef<Path>,
+        S: AsRef<Path>,
+    {
+        let idx = File::open(&idx_path).map_err(|err| Error::from(err).in_file(&idx_path))?;
+        let name = FChatIndex::read_header_from_buf(&mut BufReader::new(idx)).map_err(|err| err.in_file(&idx_path))?.name;
+        let file_name = log_path.as_ref().file_name().map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+        let name = match FChatConversationKind::classify(&file_name, Some(&name)) {
+            FChatConversationKind::Private => self.pseudonym(&name),
+            _ => name,
+        };
+        for message in FChatMessageReader::from_path(&log_path)?.with_options(STRUCTURAL_READ) {
+            self.learn(&message?);
+        }
+
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true).truncate(true);
+        let out_log = options.open(&out_log_path).map_err(|err| Error::from(err).in_file(&out_log_path))?;
+        let out_idx = options.open(&out_idx_path).map_err(|err| Error::from(err).in_file(&out_idx_path))?;
+        let mut writer = FChatWriter::new(&out_log, &out_idx, name)?;
+        // Keep the original order, even where the client wrote messages out of order.
+        writer.options = FChatWriterOptions {
+            ordering: FChatWriterOrdering::Allow,
+            split_long_messages: true,
+            ..FChatWriterOptions::default()
+        };
+        let mut written = 0;
+        for message in FChatMessageReader::from_path(&log_path)?.with_options(STRUCTURAL_READ) {
+            writer.write_message(self.anonymise(&message?))?;
+            written += 1;
+        }
+        out_log.sync_all()?;
+        out_idx.sync_all()?;
+        Ok(written)
+    }
+}
@@ -0,0 +1,276 @@
+//! Reading logs straight out of gzip or zstd compressed files, and out of tar or zip archives of a data directory,
+//! without unpacking them first. Compressed streams can't be seeked, so they're read forwards only.
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Cursor, Read};
+use std::path::{Path, PathBuf};
+use crate::error::{Error, InvalidArchive};
+use crate::fchat_index::FChatIndex;
+use crate::profile::FChatConversationKind;
+use crate::FChatMessageReader;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+/// Decompress `reader` if it's gzip or zstd compressed, going by its first bytes. Anything else is passed through.
+pub fn decompress<'a, R: Read + 'a>(reader: R) -> Result<Box<dyn Read + 'a>, Error> {
+    let mut reader = BufReader::new(reader);
+    let start = reader.fill_buf()?;
+    if start.starts_with(GZIP_MAGIC) {
+        Ok(Box::new(flate2::read::MultiGzDecoder::new(reader)))
+    } else if start.starts_with(ZSTD_MAGIC) {
+        Ok(Box::new(zstd::stream::read::Decoder::with_buffer(reader)?))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// Read the log at `path`, which may be compressed. Errors from reading it carry the path.
+pub fn read_compressed_log<P: AsRef<Path>>(path: P) -> Result<FChatMessageReader<'static>, Error> {
+    let file = File::open(&path).map_err(|err| Error::from(err).in_file(&path))?;
+    let reader = decompress(file).map_err(|err| err.in_file(&path))?;
+    Ok(FChatMessageReader::new(reader).with_path(path))
+}
+
+/// A log found in an archive, along with its idx if it has one. Paths are as stored in the archive, with `/` between
+/// directories.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArchivedConversation {
+    pub log_path: String,
+    pub idx_path: Option<String>,
+    /// Uncompressed size of the log.
+    pub size: u64,
+}
+
+impl ArchivedConversation {
+    /// The log's file name, which F-Chat names after the conversation.
+    pub fn file_name(&self) -> &str {
+        self.log_path.rsplit('/').next().unwrap_or(&self.log_path)
+    }
+
+    /// What the conversation is with, going by the log's file name alone.
+    pub fn kind(&self) -> FChatConversationKind {
+        FChatConversationKind::classify(self.file_name(), None)
+    }
+
+    /// The character whose profile the log is in, for archives laid out like F-Chat's data directory, i.e.
+    /// `<character>/logs/<conversation>`.
+    pub fn character(&self) -> Option<&str> {
+        let mut parents = self.log_path.rsplit('/').skip(1);
+        match (parents.next(), parents.next()) {
+            (Some("logs"), Some(character)) => Some(character),
+            _ => None,
+        }
+    }
+}
+
+enum ArchiveKind {
+    Tar,
+    Zip,
+}
+
+/// A tar archive, compressed or not, or a zip archive holding logs.
+pub struct FChatArchive {
+    path: PathBuf,
+    kind: ArchiveKind,
+    conversations: Vec<ArchivedConversation>,
+}
+
+impl FChatArchive {
+    /// Open the archive at `path` and find the logs in it. A file counts as a log if there's an idx next to it, or if
+    /// it's in a `logs` directory.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        Self::list(&path).map_err(|err| err.in_file(&path))
+    }
+
+    fn list(path: &Path) -> Result<Self, Error> {
+        let mut start = [0; 4];
+        let is_zip = File::open(path)?.read_exact(&mut start).is_ok() && start == ZIP_MAGIC;
+        let mut files: Vec<(String, u64)> = Vec::new();
+        let kind = if is_zip {
+            let mut zip = zip::ZipArchive::new(File::open(path)?).map_err(invalid)?;
+            for n in 0..zip.len() {
+                let file = zip.by_index(n).map_err(invalid)?;
+                if file.is_file() {
+                    files.push((checked_name(file.name())?, file.size()));
+                }
+            }
+            ArchiveKind::Zip
+        } else {
+            let mut tar = tar::Archive::new(decompress(File::open(path)?)?);
+            for entry in tar.entries()? {
+                let entry = entry?;
+                if entry.header().entry_type().is_file() {
+                    files.push((entry_path(&entry)?, entry.size()));
+                }
+            }
+            ArchiveKind::Tar
+        };
+        Ok(FChatArchive { path: path.to_path_buf(), kind, conversations: find_conversations(files) })
+    }
+
+    pub fn conversations(&self) -> &[ArchivedConversation] {
+        &self.conversations
+    }
+
+    /// Read a log out of the archive. The whole log is unpacked into memory, so prefer
+    /// [for_each_log](#method.for_each_log) for going through every log in a big tar archive.
+    pub fn read_log(&self, conversation: &ArchivedConversation) -> Result<FChatMessageReader<'static>, Error> {
+        let log = self.read_file(&conversation.log_path)?;
+        Ok(FChatMessageReader::new(Cursor::new(log)).with_path(self.path.join(&conversation.log_path)))
+    }
+
+    /// Read a log's idx out of the archive, if it has one.
+    pub fn read_index(&self, conversation: &ArchivedConversation) -> Result<Option<FChatIndex>, Error> {
+        match &conversation.idx_path {
+            Some(idx_path) => {
+                let idx = self.read_file(idx_path)?;
+                let index = FChatIndex::from_buf(&mut Cursor::new(idx)).map_err(|err| err.in_file(self.path.join(idx_path)))?;
+                Ok(Some(index))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Read any file out of the archive by its path in it.
+    pub fn read_file(&self, name: &str) -> Result<Vec<u8>, Error> {
+        let mut contents = Vec::new();
+        let found = match self.kind {
+            ArchiveKind::Zip => {
+                // Names are looked up as they were listed, so one stored with `\` is found by its `/` path.
+                let mut zip = zip::ZipArchive::new(File::open(&self.path)?).map_err(invalid)?;
+                let mut found = false;
+                for n in 0..zip.len() {
+                    let mut file = zip.by_index(n).map_err(invalid)?;
+                    if file.is_file() && checked_name(file.name())? == name {
+                        file.read_to_end(&mut contents)?;
+                        found = true;
+                        break;
+                    }
+                }
+                found
+            }
+            ArchiveKind::Tar => {
+                let mut tar = tar::Archive::new(decompress(File::open(&self.path)?)?);
+                let mut found = false;
+                for entry in tar.entries()? {
+                    let mut entry = entry?;
+                    if entry_path(&entry)? == name {
+                        entry.read_to_end(&mut contents)?;
+                        found = true;
+                        break;
+                    }
+                }
+                found
+            }
+        };
+        if !found {
+            return Err(invalid(format!("{} isn't in the archive", name)));
+        }
+        Ok(contents)
+    }
+
+    /// Go through every file in one pass over the archive, in the order they're stored.
+    pub fn for_each_file<F>(&self, mut visit: F) -> Result<(), Error>
+    where
+        F: FnMut(&str, &mut dyn Read) -> Result<(), Error>,
+    {
+        match self.kind {
+            ArchiveKind::Zip => {
+                let mut zip = zip::ZipArchive::new(File::open(&self.path)?).map_err(invalid)?;
+                for n in 0..zip.len() {
+                    let mut file = zip.by_index(n).map_err(invalid)?;
+                    if file.is_file() {
+                        let name = checked_name(file.name())?;
+                        visit(&name, &mut file)?;
+                    }
+                }
+            }
+            ArchiveKind::Tar => {
+                let mut tar = tar::Archive::new(decompress(File::open(&self.path)?)?);
+                for entry in tar.entries()? {
+                    let mut entry = entry?;
+                    if entry.header().entry_type().is_file() {
+                        let name = entry_path(&entry)?;
+                        visit(&name, &mut entry)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Go through every log in one pass over the archive, reading each straight from it without unpacking it first.
+    pub fn for_each_log<F>(&self, mut visit: F) -> Result<(), Error>
+    where
+        F: FnMut(&ArchivedConversation, FChatMessageReader<'_>) -> Result<(), Error>,
+    {
+        let conversation = |name: &str| self.conversations.iter().find(|conversation| conversation.log_path == name);
+        match self.kind {
+            ArchiveKind::Zip => {
+                let mut zip = zip::ZipArchive::new(File::open(&self.path)?).map_err(invalid)?;
+                for n in 0..zip.len() {
+                    let file = zip.by_index(n).map_err(invalid)?;
+                    if let Some(conversation) = conversation(&checked_name(file.name())?) {
+                        let path = self.path.join(&conversation.log_path);
+                        visit(conversation, FChatMessageReader::new(file).with_path(path))?;
+                    }
+                }
+            }
+            ArchiveKind::Tar => {
+                let mut tar = tar::Archive::new(decompress(File::open(&self.path)?)?);
+                for entry in tar.entries()? {
+                    let entry = entry?;
+                    if let Some(conversation) = conversation(&entry_path(&entry)?) {
+                        let path = self.path.join(&conversation.log_path);
+                        visit(conversation, FChatMessageReader::new(entry).with_path(path))?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn entry_path<R: Read>(entry: &tar::Entry<R>) -> Result<String, Error> {
+    checked_name(&entry.path()?.to_string_lossy())
+}
+
+/// A path as stored in an archive, with `/` between directories. Paths that are absolute or go up out of the archive
+/// are refused, so nothing unpacking files by their path can be made to write outside where it's unpacking to.
+fn checked_name(name: &str) -> Result<String, Error> {
+    let name = name.replace('\\', "/");
+    let first = name.split('/').next().unwrap_or_default();
+    if name.starts_with('/') || first.ends_with(':') || name.split('/').any(|part| part == "..") {
+        return Err(invalid(format!("{} points outside the archive", name)));
+    }
+    Ok(name)
+}
+
+fn find_conversations(files: Vec<(String, u64)>) -> Vec<ArchivedConversation> {
+    let names: BTreeSet<&str> = files.iter().map(|(name, _)| name.as_str()).collect();
+    let mut conversations: Vec<ArchivedConversation> = files.iter().filter_map(|(name, size)| {
+        if name.ends_with(".idx") {
+            return None;
+        }
+        let idx_path = format!("{}.idx", name);
+        let has_idx = names.contains(idx_path.as_str());
+        let in_logs = name.rsplit('/').nth(1) == Some("logs");
+        if !has_idx && !in_logs {
+            return None;
+        }
+        Some(ArchivedConversation {
+            log_path: name.clone(),
+            idx_path: if has_idx { Some(idx_path) } else { None },
+            size: *size,
+        })
+    }).collect();
+    conversations.sort_by(|a, b| a.log_path.cmp(&b.log_path));
+    conversations
+}
+
+fn invalid<E: ToString>(err: E) -> Error {
+    Error::from(InvalidArchive { reason: err.to_string() })
+}
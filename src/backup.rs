@@ -0,0 +1,660 @@
+//! Backing up a whole [profile](../profile/struct.FChatProfile.html) into one bundle, and restoring it.
+//!
+//! A bundle is a gzipped tar holding a manifest, `manifest.tsv`, followed by every log and idx under `logs/`. The
+//! manifest records each conversation's name, message count, date span and checksums, so a restore can check nothing
+//! was damaged before touching the profile.
+//!
+//! Logs are only ever appended to, so [increments](fn.backup_increment.html) hold just what was added to each log since
+//! the last backup, and are put back together on top of a base.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use chrono::{DateTime, NaiveDateTime};
+use sha2::{Digest, Sha256};
+use crate::archive::FChatArchive;
+use crate::error::{ConformanceError, Error, InvalidArchive};
+use crate::fchat_message::{FChatMessage, FChatMessageKind, STRUCTURAL_READ};
+use crate::profile::{FChatConversation, FChatProfile};
+use crate::{intact_log_len, FChatMessageCursor, FChatMessageReader, FChatWriter, FChatWriterOptions, FChatWriterOrdering};
+
+const MANIFEST_PATH: &str = "manifest.tsv";
+const MANIFEST_HEADER: &str = "# fchat3-log-lib backup manifest v1";
+const INCREMENT_PATH: &str = "increment.tsv";
+const INCREMENT_HEADER: &str = "# fchat3-log-lib backup increment v1";
+const LOGS_DIR: &str = "logs/";
+
+/// What the manifest records about one conversation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ManifestEntry {
+    pub file_name: String,
+    /// The name in the idx header.
+    pub name: String,
+    /// Messages in the log, leaving out a record torn at its end.
+    pub messages: u64,
+    pub first_message: Option<NaiveDateTime>,
+    pub last_message: Option<NaiveDateTime>,
+    /// SHA-256 of the log and idx, in hex.
+    pub log_sha256: String,
+    pub idx_sha256: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BackupManifest {
+    pub character: String,
+    pub conversations: Vec<ManifestEntry>,
+}
+
+impl BackupManifest {
+    fn to_tsv(&self) -> String {
+        let mut tsv = format!("{}\ncharacter\t{}\n", MANIFEST_HEADER, escape(&self.character));
+        for entry in &self.conversations {
+            let timestamp = |datetime: Option<NaiveDateTime>| datetime.map_or(String::new(), |datetime| datetime.and_utc().timestamp().to_string());
+            tsv.push_str(&format!(
+                "conversation\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                escape(&entry.file_name), escape(&entry.name), entry.messages,
+                timestamp(entry.first_message), timestamp(entry.last_message),
+                entry.log_sha256, entry.idx_sha256.as_deref().unwrap_or(""),
+            ));
+        }
+        tsv
+    }
+
+    fn from_tsv(tsv: &str) -> Result<Self, Error> {
+        let mut lines = tsv.lines();
+        if lines.next() != Some(MANIFEST_HEADER) {
+            return Err(invalid("the manifest is missing or from an unsupported version"));
+        }
+        let mut manifest = BackupManifest::default();
+        for line in lines {
+            let fields: Vec<&str> = line.split('\t').collect();
+            match fields.as_slice() {
+                ["character", character] => manifest.character = unescape(character),
+                ["conversation", file_name, name, messages, first, last, log_sha256, idx_sha256] => {
+                    let timestamp = |field: &str| -> Result<Option<NaiveDateTime>, Error> {
+                        if field.is_empty() {
+                            return Ok(None);
+                        }
+                        let seconds = field.parse().map_err(|_| invalid("a timestamp in the manifest is damaged"))?;
+                        Ok(DateTime::from_timestamp(seconds, 0).map(|datetime| datetime.naive_utc()))
+                    };
+                    let file_name = unescape(file_name);
+                    check_file_name(&file_name)?;
+                    manifest.conversations.push(ManifestEntry {
+                        file_name,
+                        name: unescape(name),
+                        messages: messages.parse().map_err(|_| invalid("a message count in the manifest is damaged"))?,
+                        first_message: timestamp(first)?,
+                        last_message: timestamp(last)?,
+                        log_sha256: log_sha256.to_string(),
+                        idx_sha256: if idx_sha256.is_empty() { None } else { Some(idx_sha256.to_string()) },
+                    });
+                }
+                _ => return Err(invalid("a line in the manifest is damaged")),
+            }
+        }
+        Ok(manifest)
+    }
+}
+
+/// Check a file name from a manifest is just a file name, so restoring it can't write outside the logs directory.
+fn check_file_name(file_name: &str) -> Result<(), Error> {
+    let mut components = Path::new(file_name).components();
+    let plain = matches!((components.next(), components.next()), (Some(Component::Normal(_)), None));
+    if !plain || file_name.contains(['/', '\\']) {
+        return Err(invalid(&format!("\"{}\" in the manifest isn't a plain file name", escape(file_name))));
+    }
+    Ok(())
+}
+
+/// What a [restore](fn.restore_bundle.html) did.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FChatRestoreReport {
+    /// Conversations the profile didn't have yet.
+    pub conversations_added: u64,
+    /// Conversations the profile had, which got the messages it was missing.
+    pub conversations_merged: u64,
+    pub messages_added: u64,
+    /// Messages left out because the profile already had them.
+    pub messages_skipped: u64,
+}
+
+/// Write every log and idx in `profile` to a bundle at `bundle_path`, replacing anything already there.
+pub fn backup_profile<P: AsRef<Path>>(profile: &FChatProfile, bundle_path: P) -> Result<BackupManifest, Error> {
+    let mut manifest = BackupManifest {
+        character: profile.character().unwrap_or_default().to_string(),
+        conversations: Vec::new(),
+    };
+    // The client may be appending while this runs, so only the whole records there now are backed up, the same bytes
+    // every pass.
+    let mut sizes = Vec::new();
+    for conversation in profile.conversations()? {
+        let mut log = File::open(&conversation.log_path).map_err(|err| Error::from(err).in_file(&conversation.log_path))?;
+        let log_len = intact_log_len(&mut log, 0).map_err(|err| err.in_file(&conversation.log_path))?;
+        let idx_len = if conversation.has_idx() { Some(file_len(&conversation.idx_path)?) } else { None };
+        manifest.conversations.push(describe(&conversation, log_len, idx_len)?);
+        sizes.push((conversation, log_len, idx_len));
+    }
+
+    let bundle = File::create(&bundle_path).map_err(|err| Error::from(err).in_file(&bundle_path))?;
+    let mut tar = tar::Builder::new(flate2::write::GzEncoder::new(bundle, flate2::Compression::default()));
+    let manifest_tsv = manifest.to_tsv();
+    append(&mut tar, MANIFEST_PATH, manifest_tsv.len() as u64, manifest_tsv.as_bytes())?;
+    for (conversation, log_len, idx_len) in sizes {
+        let log = File::open(&conversation.log_path).map_err(|err| Error::from(err).in_file(&conversation.log_path))?;
+        append(&mut tar, &format!("{}{}", LOGS_DIR, conversation.file_name), log_len, log.take(log_len))?;
+        if let Some(idx_len) = idx_len {
+            let idx = File::open(&conversation.idx_path).map_err(|err| Error::from(err).in_file(&conversation.idx_path))?;
+            append(&mut tar, &format!("{}{}.idx", LOGS_DIR, conversation.file_name), idx_len, idx.take(idx_len))?;
+        }
+    }
+    tar.into_inner()?.finish()?.sync_all()?;
+    Ok(manifest)
+}
+
+fn file_len(path: &Path) -> Result<u64, Error> {
+    Ok(fs::metadata(path).map_err(|err| Error::from(err).in_file(path))?.len())
+}
+
+/// What the manifest records about the first `log_len` and `idx_len` bytes of a conversation's log and idx.
+fn describe(conversation: &FChatConversation, log_len: u64, idx_len: Option<u64>) -> Result<ManifestEntry, Error> {
+    let open = |path: &Path, len: u64| -> Result<io::Take<File>, Error> {
+        Ok(File::open(path).map_err(|err| Error::from(err).in_file(path))?.take(len))
+    };
+    let mut entry = ManifestEntry {
+        file_name: conversation.file_name.clone(),
+        name: conversation.file_name.clone(),
+        messages: 0,
+        first_message: None,
+        last_message: None,
+        log_sha256: sha256(open(&conversation.log_path, log_len)?)?,
+        idx_sha256: None,
+    };
+    if let Some(idx_len) = idx_len {
+        entry.idx_sha256 = Some(sha256(open(&conversation.idx_path, idx_len)?)?);
+        if let Ok(index) = conversation.index() {
+            entry.name = index.name;
+        }
+    }
+    let reader = FChatMessageReader::new(io::BufReader::new(open(&conversation.log_path, log_len)?))
+        .with_path(&conversation.log_path)
+        .with_options(STRUCTURAL_READ);
+    for message in reader {
+        let message = message?;
+        entry.messages += 1;
+        entry.first_message = Some(entry.first_message.map_or(message.datetime, |first| first.min(message.datetime)));
+        entry.last_message = Some(entry.last_message.map_or(message.datetime, |last| last.max(message.datetime)));
+    }
+    Ok(entry)
+}
+
+fn append<W: Write, R: Read>(tar: &mut tar::Builder<W>, path: &str, len: u64, data: R) -> Result<(), Error> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(len);
+    header.set_mode(0o644);
+    header.set_cksum();
+    tar.append_data(&mut header, path, data)?;
+    Ok(())
+}
+
+/// The manifest of the bundle at `bundle_path`.
+pub fn read_manifest<P: AsRef<Path>>(bundle_path: P) -> Result<BackupManifest, Error> {
+    let bundle = FChatArchive::open(&bundle_path)?;
+    let manifest = bundle.read_file(MANIFEST_PATH).map_err(|err| err.in_file(&bundle_path))?;
+    BackupManifest::from_tsv(&String::from_utf8_lossy(&manifest)).map_err(|err| err.in_file(&bundle_path))
+}
+
+/// Restore the bundle at `bundle_path` into `profile`. Every checksum is checked before anything is written. Logs the
+/// profile doesn't have are copied from the bundle as they are, and logs it does have get the messages from the bundle
+/// they're missing, in timestamp order. Either way their idx is rebuilt. Logs are read the way the backup read them, so
+/// bad UTF-8 and a record torn at the end of a log in the profile don't stop a restore.
+pub fn restore_bundle<P: AsRef<Path>>(bundle_path: P, profile: &FChatProfile) -> Result<FChatRestoreReport, Error> {
+    let manifest = read_manifest(&bundle_path)?;
+    let bundle = FChatArchive::open(&bundle_path)?;
+    let mut expected: HashMap<String, &str> = HashMap::new();
+    for entry in &manifest.conversations {
+        expected.insert(format!("{}{}", LOGS_DIR, entry.file_name), &entry.log_sha256);
+        if let Some(idx_sha256) = &entry.idx_sha256 {
+            expected.insert(format!("{}{}.idx", LOGS_DIR, entry.file_name), idx_sha256);
+        }
+    }
+    verify(&bundle, &expected).map_err(|err| err.in_file(&bundle_path))?;
+
+    let logs_dir = profile.logs_dir();
+    fs::create_dir_all(&logs_dir).map_err(|err| Error::from(err).in_file(&logs_dir))?;
+    let mut report = FChatRestoreReport::default();
+    bundle.for_each_file(|name, reader| {
+        let entry = match name.strip_prefix(LOGS_DIR) {
+            Some(file_name) => manifest.conversations.iter().find(|entry| entry.file_name == file_name),
+            None => None,
+        };
+        if let Some(entry) = entry {
+            let conversation = profile.conversation(&entry.file_name);
+            if conversation.log_path.exists() {
+                let incoming = read_leniently(FChatMessageReader::new(reader))?;
+                merge(&conversation, &entry.name, incoming, &mut report)?;
+            } else {
+                replace_log(&conversation, |mut log, idx| {
+                    io::copy(reader, &mut log)?;
+                    // A bundle that wasn't made by backup_profile can still end with a torn record.
+                    let log_len = intact_log_len(&mut log, 0)?;
+                    log.set_len(log_len)?;
+                    log.seek(SeekFrom::Start(0))?;
+                    FChatWriter::from_log(log, idx, entry.name.clone())?;
+                    Ok(())
+                })?;
+                report.conversations_added += 1;
+                report.messages_added += entry.messages;
+            }
+        }
+        Ok(())
+    }).map_err(|err| err.in_file(&bundle_path))?;
+    Ok(report)
+}
+
+/// Check every file in `expected`, by its path in the bundle, is there and matches its checksum.
+fn verify(bundle: &FChatArchive, expected: &HashMap<String, &str>) -> Result<(), Error> {
+    let mut verified = 0;
+    bundle.for_each_file(|name, reader| {
+        if let Some(checksum) = expected.get(name) {
+            if sha256(reader)? != *checksum {
+                return Err(invalid(&format!("{} doesn't match its checksum", name)));
+            }
+            verified += 1;
+        }
+        Ok(())
+    })?;
+    if verified != expected.len() {
+        return Err(invalid("files listed in the manifest are missing"));
+    }
+    Ok(())
+}
+
+type MessageKey = (NaiveDateTime, String, FChatMessageKind, String);
+
+fn key(message: &FChatMessage) -> MessageKey {
+    (message.datetime, message.sender.clone(), message.body.kind(), message.body.text().to_string())
+}
+
+/// Every message `reader` has, read like the backup reads them: with bad UTF-8 replaced, messages of unknown types
+/// kept, and a record torn at the end left out.
+fn read_leniently(reader: FChatMessageReader<'_>) -> Result<Vec<FChatMessage>, Error> {
+    let mut messages = Vec::new();
+    for message in reader.with_options(STRUCTURAL_READ) {
+        match message {
+            Ok(message) => messages.push(message),
+            Err(err) if matches!(err.root(), Error::TruncatedError(_)) => break,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(messages)
+}
+
+/// Add the messages `conversation` doesn't already have. A message counts as had if the log has as many copies of it
+/// as the incoming messages do, so messages legitimately sent twice aren't lost.
+fn merge(conversation: &FChatConversation, name: &str, incoming: Vec<FChatMessage>, report: &mut FChatRestoreReport) -> Result<(), Error> {
+    let existing = read_leniently(conversation.reader()?)?;
+    let mut had: HashMap<MessageKey, u64> = HashMap::new();
+    for message in &existing {
+        *had.entry(key(message)).or_insert(0) += 1;
+    }
+    let mut missing = Vec::new();
+    for message in incoming {
+        match had.get_mut(&key(&message)) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                report.messages_skipped += 1;
+            }
+            _ => missing.push(message),
+        }
+    }
+    if missing.is_empty() {
+        return Ok(());
+    }
+    report.messages_added += missing.len() as u64;
+    report.conversations_merged += 1;
+    let name = if conversation.has_idx() { conversation.index().map_or(name.to_string(), |index| index.name) } else { name.to_string() };
+
+    // Merge the two in timestamp order, keeping the order each was in, into new files that then replace the old ones.
+    let mut merged = Vec::with_capacity(existing.len() + missing.len());
+    let mut existing = existing.into_iter().peekable();
+    let mut missing = missing.into_iter().peekable();
+    while let (Some(a), Some(b)) = (existing.peek(), missing.peek()) {
+        merged.push(if a.datetime <= b.datetime { existing.next() } else { missing.next() }.expect("peeked"));
+    }
+    merged.extend(existing.chain(missing));
+    replace_log(conversation, |log, idx| {
+        let mut writer = FChatWriter::new(log, idx, name)?;
+        writer.options = FChatWriterOptions { ordering: FChatWriterOrdering::Allow, ..FChatWriterOptions::default() };
+        writer.write_batch(merged)
+    })
+}
+
+/// Have `write` write a new log and idx for `conversation`, then put them in place of the ones in the profile. They're
+/// written next to them first, and removed again if anything goes wrong.
+fn replace_log<F>(conversation: &FChatConversation, write: F) -> Result<(), Error>
+where
+    F: FnOnce(&File, &File) -> Result<(), Error>,
+{
+    let (log_tmp, idx_tmp) = restore_paths(conversation);
+    let result = (|| {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true).truncate(true);
+        let log = options.open(&log_tmp).map_err(|err| Error::from(err).in_file(&log_tmp))?;
+        let idx = options.open(&idx_tmp).map_err(|err| Error::from(err).in_file(&idx_tmp))?;
+        write(&log, &idx).map_err(|err| err.in_file(&log_tmp))?;
+        log.sync_all()?;
+        idx.sync_all()?;
+        fs::rename(&log_tmp, &conversation.log_path).map_err(|err| Error::from(err).in_file(&conversation.log_path))?;
+        fs::rename(&idx_tmp, &conversation.idx_path).map_err(|err| Error::from(err).in_file(&conversation.idx_path))
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(log_tmp);
+        let _ = fs::remove_file(idx_tmp);
+    }
+    result
+}
+
+/// How far one log has been backed up.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CheckpointEntry {
+    pub len: u64,
+    /// SHA-256 of the last record backed up, in hex, to tell the log still starts with what was backed up.
+    pub last_record_sha256: String,
+}
+
+/// How far each log, by file name, has been backed up so far.
+pub type BackupCheckpoint = BTreeMap<String, CheckpointEntry>;
+
+/// What an increment holds of one log: the bytes from `start` to `end`, appended since the backup before it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IncrementEntry {
+    pub file_name: String,
+    /// The name in the idx header.
+    pub name: String,
+    pub start: u64,
+    pub end: u64,
+    /// SHA-256 of the appended bytes, in hex.
+    pub tail_sha256: String,
+    /// SHA-256 of the last record before `end`, in hex.
+    pub last_record_sha256: String,
+}
+
+/// The manifest of an incremental backup. An increment taken from an empty checkpoint holds whole logs, and serves as
+/// the base the ones after it build on.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BackupIncrement {
+    pub character: String,
+    pub conversations: Vec<IncrementEntry>,
+}
+
+impl BackupIncrement {
+    /// How far every log has been backed up, to take the next increment from.
+    pub fn checkpoint(&self) -> BackupCheckpoint {
+        self.conversations.iter().map(|entry| {
+            (entry.file_name.clone(), CheckpointEntry { len: entry.end, last_record_sha256: entry.last_record_sha256.clone() })
+        }).collect()
+    }
+
+    fn to_tsv(&self) -> String {
+        let mut tsv = format!("{}\ncharacter\t{}\n", INCREMENT_HEADER, escape(&self.character));
+        for entry in &self.conversations {
+            tsv.push_str(&format!(
+                "conversation\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                escape(&entry.file_name), escape(&entry.name), entry.start, entry.end, entry.tail_sha256, entry.last_record_sha256,
+            ));
+        }
+        tsv
+    }
+
+    fn from_tsv(tsv: &str) -> Result<Self, Error> {
+        let mut lines = tsv.lines();
+        if lines.next() != Some(INCREMENT_HEADER) {
+            return Err(invalid("the manifest is missing or from an unsupported version"));
+        }
+        let mut increment = BackupIncrement::default();
+        for line in lines {
+            let fields: Vec<&str> = line.split('\t').collect();
+            match fields.as_slice() {
+                ["character", character] => increment.character = unescape(character),
+                ["conversation", file_name, name, start, end, tail_sha256, last_record_sha256] => {
+                    let offset = |field: &str| field.parse::<u64>().map_err(|_| invalid("an offset in the manifest is damaged"));
+                    let (start, end) = (offset(start)?, offset(end)?);
+                    if end < start {
+                        return Err(invalid("an offset in the manifest is damaged"));
+                    }
+                    let file_name = unescape(file_name);
+                    check_file_name(&file_name)?;
+                    increment.conversations.push(IncrementEntry {
+                        file_name,
+                        name: unescape(name),
+                        start,
+                        end,
+                        tail_sha256: tail_sha256.to_string(),
+                        last_record_sha256: last_record_sha256.to_string(),
+                    });
+                }
+                _ => return Err(invalid("a line in the manifest is damaged")),
+            }
+        }
+        Ok(increment)
+    }
+}
+
+/// Write what's been appended to every log in `profile` since `checkpoint` to a bundle at `bundle_path`, replacing
+/// anything already there. Logs not in the checkpoint are backed up whole. A log that was shortened, or whose last
+/// record backed up changed, is an error, and needs a new base backup.
+pub fn backup_increment<P: AsRef<Path>>(profile: &FChatProfile, checkpoint: &BackupCheckpoint, bundle_path: P) -> Result<BackupIncrement, Error> {
+    let mut increment = BackupIncrement {
+        character: profile.character().unwrap_or_default().to_string(),
+        conversations: Vec::new(),
+    };
+    let mut tails = Vec::new();
+    for conversation in profile.conversations()? {
+        let in_log = |err: Error| err.in_file(&conversation.log_path);
+        let mut log = File::open(&conversation.log_path).map_err(|err| in_log(err.into()))?;
+        let end = appended_len(&mut log, checkpoint.get(&conversation.file_name)).map_err(in_log)?;
+        let start = checkpoint.get(&conversation.file_name).map_or(0, |backed_up| backed_up.len);
+        log.seek(SeekFrom::Start(start))?;
+        let tail_sha256 = sha256((&mut log).take(end - start)).map_err(in_log)?;
+        increment.conversations.push(IncrementEntry {
+            file_name: conversation.file_name.clone(),
+            name: conversation.index().map_or_else(|_| conversation.file_name.clone(), |index| index.name),
+            start,
+            end,
+            tail_sha256,
+            last_record_sha256: last_record_sha256(&mut log, end).map_err(in_log)?,
+        });
+        if end > start {
+            tails.push((conversation, start, end));
+        }
+    }
+    // Logs gone from the profile keep their place, so one that comes back isn't mistaken for the same log.
+    for (file_name, backed_up) in checkpoint {
+        if !increment.conversations.iter().any(|entry| &entry.file_name == file_name) {
+            increment.conversations.push(IncrementEntry {
+                file_name: file_name.clone(),
+                name: file_name.clone(),
+                start: backed_up.len,
+                end: backed_up.len,
+                tail_sha256: sha256(io::empty())?,
+                last_record_sha256: backed_up.last_record_sha256.clone(),
+            });
+        }
+    }
+    increment.conversations.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+
+    let bundle = File::create(&bundle_path).map_err(|err| Error::from(err).in_file(&bundle_path))?;
+    let mut tar = tar::Builder::new(flate2::write::GzEncoder::new(bundle, flate2::Compression::default()));
+    let increment_tsv = increment.to_tsv();
+    append(&mut tar, INCREMENT_PATH, increment_tsv.len() as u64, increment_tsv.as_bytes())?;
+    for (conversation, start, end) in tails {
+        let mut log = File::open(&conversation.log_path).map_err(|err| Error::from(err).in_file(&conversation.log_path))?;
+        log.seek(SeekFrom::Start(start))?;
+        append(&mut tar, &format!("{}{}", LOGS_DIR, conversation.file_name), end - start, log.take(end - start))?;
+    }
+    tar.into_inner()?.finish()?.sync_all()?;
+    Ok(increment)
+}
+
+/// How much of `log` can be backed up, going on from where the last backup stopped. A record the client is still
+/// writing is left for the next backup.
+fn appended_len(log: &mut File, backed_up: Option<&CheckpointEntry>) -> Result<u64, Error> {
+    let rewritten = |reason: &str| Error::ConformanceError(ConformanceError { reason: reason.to_string() });
+    let start = backed_up.map_or(0, |backed_up| backed_up.len);
+    if log.seek(SeekFrom::End(0))? < start {
+        return Err(rewritten("the log is shorter than when it was last backed up"));
+    }
+    if let Some(backed_up) = backed_up {
+        if last_record_sha256(log, start).ok().as_ref() != Some(&backed_up.last_record_sha256) {
+            return Err(rewritten("the log was rewritten since it was last backed up"));
+        }
+    }
+    intact_log_len(log, start)
+}
+
+/// SHA-256 of the record that ends at `end`, or of nothing if `end` is the start of the log.
+fn last_record_sha256(log: &mut File, end: u64) -> Result<String, Error> {
+    let start = match FChatMessageCursor::at(&mut *log, end)?.with_options(STRUCTURAL_READ).previous_message() {
+        Some(record) => record?.0,
+        None => end,
+    };
+    log.seek(SeekFrom::Start(start))?;
+    sha256(log.take(end - start))
+}
+
+/// The manifest of the increment at `bundle_path`.
+pub fn read_increment<P: AsRef<Path>>(bundle_path: P) -> Result<BackupIncrement, Error> {
+    let bundle = FChatArchive::open(&bundle_path)?;
+    let increment = bundle.read_file(INCREMENT_PATH).map_err(|err| err.in_file(&bundle_path))?;
+    BackupIncrement::from_tsv(&String::from_utf8_lossy(&increment)).map_err(|err| err.in_file(&bundle_path))
+}
+
+/// Put the logs in `profile` back together from a base and the increments taken after it, in the order they were
+/// taken, and rebuild their idx. The logs replace any the profile has. Each bundle has to go on from where the ones
+/// before it stopped and match its checksums, or nothing in the profile is changed. Returns how far each log was put
+/// back, to take the next increment from.
+pub fn restore_increments<P: AsRef<Path>>(bundle_paths: &[P], profile: &FChatProfile) -> Result<BackupCheckpoint, Error> {
+    let mut started = BTreeSet::new();
+    let result = assemble_increments(bundle_paths, profile, &mut started);
+    if result.is_err() {
+        for file_name in started {
+            let (log_tmp, idx_tmp) = restore_paths(&profile.conversation(&file_name));
+            let _ = fs::remove_file(log_tmp);
+            let _ = fs::remove_file(idx_tmp);
+        }
+    }
+    result
+}
+
+/// [restore_increments](fn.restore_increments.html), noting in `started` every log it starts putting together.
+fn assemble_increments<P: AsRef<Path>>(bundle_paths: &[P], profile: &FChatProfile, started: &mut BTreeSet<String>) -> Result<BackupCheckpoint, Error> {
+    let logs_dir = profile.logs_dir();
+    fs::create_dir_all(&logs_dir).map_err(|err| Error::from(err).in_file(&logs_dir))?;
+    let mut assembled: BTreeMap<String, (IncrementEntry, String)> = BTreeMap::new();
+    for bundle_path in bundle_paths {
+        let bundle_path = bundle_path.as_ref();
+        let increment = read_increment(bundle_path)?;
+        let bundle = FChatArchive::open(bundle_path)?;
+        let mut expected: HashMap<String, &str> = HashMap::new();
+        for entry in &increment.conversations {
+            let assembled_len = assembled.get(&entry.file_name).map_or(0, |(last, _)| last.end);
+            if entry.start != assembled_len {
+                let reason = format!("{} doesn't go on from where the bundles before it stopped", entry.file_name);
+                return Err(invalid(&reason).in_file(bundle_path));
+            }
+            if entry.end > entry.start {
+                expected.insert(format!("{}{}", LOGS_DIR, entry.file_name), &entry.tail_sha256);
+            }
+        }
+        verify(&bundle, &expected).map_err(|err| err.in_file(bundle_path))?;
+
+        bundle.for_each_file(|name, reader| {
+            let entry = match name.strip_prefix(LOGS_DIR) {
+                Some(file_name) => increment.conversations.iter().find(|entry| entry.file_name == file_name && entry.end > entry.start),
+                None => None,
+            };
+            if let Some(entry) = entry {
+                let (log_tmp, _) = restore_paths(&profile.conversation(&entry.file_name));
+                started.insert(entry.file_name.clone());
+                let mut log = OpenOptions::new().write(true).create(true).truncate(entry.start == 0).open(&log_tmp)
+                    .map_err(|err| Error::from(err).in_file(&log_tmp))?;
+                log.seek(SeekFrom::Start(entry.start))?;
+                io::copy(reader, &mut log)?;
+            }
+            Ok(())
+        }).map_err(|err| err.in_file(bundle_path))?;
+        for entry in &increment.conversations {
+            let (last, name) = assembled.entry(entry.file_name.clone()).or_insert((entry.clone(), entry.name.clone()));
+            *last = entry.clone();
+            if entry.end > entry.start {
+                *name = entry.name.clone();
+            }
+        }
+    }
+
+    // Everything checked out, so the logs can take the place of the ones in the profile.
+    for (file_name, (last, name)) in &assembled {
+        if last.end == 0 {
+            continue;
+        }
+        let conversation = profile.conversation(file_name);
+        let (log_tmp, idx_tmp) = restore_paths(&conversation);
+        let log = OpenOptions::new().read(true).write(true).open(&log_tmp).map_err(|err| Error::from(err).in_file(&log_tmp))?;
+        let idx = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&idx_tmp)
+            .map_err(|err| Error::from(err).in_file(&idx_tmp))?;
+        FChatWriter::from_log(&log, &idx, name.clone()).map_err(|err| err.in_file(&log_tmp))?;
+        log.sync_all()?;
+        idx.sync_all()?;
+        fs::rename(&log_tmp, &conversation.log_path).map_err(|err| Error::from(err).in_file(&conversation.log_path))?;
+        fs::rename(&idx_tmp, &conversation.idx_path).map_err(|err| Error::from(err).in_file(&conversation.idx_path))?;
+    }
+    Ok(assembled.into_iter().map(|(file_name, (last, _))| {
+        (file_name, CheckpointEntry { len: last.end, last_record_sha256: last.last_record_sha256 })
+    }).collect())
+}
+
+/// Where a conversation's log and idx are put together before they replace the ones in the profile.
+fn restore_paths(conversation: &FChatConversation) -> (PathBuf, PathBuf) {
+    (
+        conversation.log_path.with_file_name(format!(".{}.restore", conversation.file_name)),
+        conversation.idx_path.with_file_name(format!(".{}.idx.restore", conversation.file_name)),
+    )
+}
+
+fn sha256<R: Read>(mut reader: R) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+fn escape(field: &str) -> String {
+    field.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n")
+}
+
+fn unescape(field: &str) -> String {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::from(InvalidArchive { reason: reason.to_string() })
+}
@@ -0,0 +1,68 @@
+//! [Containers](../container/struct.FChatContainer.html) compressed with zstd. Days are grouped into blocks that are
+//! compressed on their own, so seeking to a day only decompresses the block it starts in instead of the whole log.
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::path::Path;
+use crate::container::{BlockCodec, FChatContainer};
+use crate::error::{Error, InvalidContainer};
+
+const MAGIC: &[u8; 8] = b"FCHATZST";
+const VERSION: u8 = 1;
+/// Blocks are at least this long, unless they're the last, so small days still compress well together.
+pub const DEFAULT_BLOCK_SIZE: u64 = 64 * 1024;
+
+/// Compresses blocks with zstd.
+pub struct CompressionCodec {
+    pub level: i32,
+    pub block_size: u64,
+}
+
+impl Default for CompressionCodec {
+    fn default() -> Self {
+        CompressionCodec {
+            level: zstd::DEFAULT_COMPRESSION_LEVEL,
+            block_size: DEFAULT_BLOCK_SIZE,
+        }
+    }
+}
+
+impl BlockCodec for CompressionCodec {
+    fn seal(&self, _context: u64, data: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(zstd::bulk::compress(data, self.level)?)
+    }
+
+    fn open(&self, _context: u64, data: &[u8]) -> Result<Vec<u8>, Error> {
+        zstd::stream::decode_all(data).map_err(|_| Error::from(InvalidContainer { reason: String::from("a block can't be decompressed") }))
+    }
+
+    fn block_size(&self) -> u64 {
+        self.block_size
+    }
+}
+
+/// A log and idx compressed with zstd.
+pub type FChatCompressedLog = FChatContainer<CompressionCodec>;
+
+impl FChatContainer<CompressionCodec> {
+    /// Create a compressed container at `path` for a conversation called `name`, replacing anything already there.
+    pub fn create_compressed<P: AsRef<Path>>(path: P, name: String, codec: CompressionCodec) -> Result<Self, Error> {
+        let mut header = MAGIC.to_vec();
+        header.push(VERSION);
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path)
+            .map_err(|err| Error::from(err).in_file(&path))?;
+        Self::create(file, &header, codec, name).map_err(|err| err.in_file(&path))
+    }
+
+    /// Open the compressed container at `path`. New blocks are written with `codec`'s settings.
+    pub fn open_compressed<P: AsRef<Path>>(path: P, codec: CompressionCodec) -> Result<Self, Error> {
+        let mut file = OpenOptions::new().read(true).write(true).open(&path)
+            .map_err(|err| Error::from(err).in_file(&path))?;
+        let mut header = [0; 9];
+        let is_compressed = file.read_exact(&mut header).is_ok() && &header[..8] == MAGIC;
+        if !is_compressed || header[8] != VERSION {
+            let reason = if is_compressed { format!("unsupported version {}", header[8]) } else { String::from("not a compressed log") };
+            return Err(Error::from(InvalidContainer { reason }).in_file(&path));
+        }
+        Self::open(file, codec).map_err(|err| err.in_file(&path))
+    }
+}
@@ -0,0 +1,473 @@
+//! A single file holding a log and its idx, with the log stored in blocks that each go through a
+//! [codec](trait.BlockCodec.html) on the way to disk, e.g. to encrypt or compress them. Blocks start where the idx says a
+//! day starts, so reading a day only has to decode the blocks it's in.
+//!
+//! The file is the codec's header, then the blocks, then a table listing the blocks along with the idx, which also goes
+//! through the codec, and finally a footer pointing at the table. Each commit appends its new blocks, a new table and a
+//! new footer after the last footer, so a commit that's cut short leaves the one before it to fall back on. When what
+//! earlier commits superseded outgrows what's live, the live blocks and table are moved back to just after the header.
+use std::borrow::Cow;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::fs::File;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crate::error::{Error, InvalidContainer};
+use crate::fchat_index::FChatIndex;
+use crate::FChatMessageCursor;
+use chrono::NaiveDate;
+
+const FOOTER_MAGIC: &[u8; 8] = b"FCBLKEND";
+const FOOTER_LEN: u64 = 8 + 8 + 8;
+/// Context the table is sealed with, so it can't be passed off as a block.
+const TABLE_CONTEXT: u64 = u64::MAX;
+/// How much of the end of the file is read at a time when looking for the last whole footer.
+const FOOTER_SCAN_LEN: u64 = 64 * 1024;
+
+/// Turns blocks into what's stored on disk and back. `context` is the offset of the block in the log, and has to be the
+/// same to get a block back out as it was when it went in.
+pub trait BlockCodec {
+    fn seal(&self, context: u64, data: &[u8]) -> Result<Vec<u8>, Error>;
+    fn open(&self, context: u64, data: &[u8]) -> Result<Vec<u8>, Error>;
+
+    /// Blocks only end at a day start once they're at least this long, so days can share a block.
+    fn block_size(&self) -> u64 {
+        0
+    }
+}
+
+/// Where a block of the log is, both in the log and in the container.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ContainerBlock {
+    pub log_offset: u64,
+    pub log_len: u64,
+    pub stored_offset: u64,
+    pub stored_len: u64,
+}
+
+/// The log in a [container](struct.FChatContainer.html). It can be read and seeked like a log file, and written to at
+/// the end. Writes are kept in memory until the container is [committed](struct.FChatContainer.html#method.commit).
+pub struct ContainerLog<C: BlockCodec> {
+    file: File,
+    codec: C,
+    blocks: Vec<ContainerBlock>,
+    /// Where the header ends and the blocks start.
+    data_start: u64,
+    /// Where the last whole footer ends, which is where the next commit goes.
+    committed_len: u64,
+    pending: Vec<u8>,
+    position: u64,
+    /// The last block decoded and its number.
+    cached: Option<(usize, Vec<u8>)>,
+}
+
+impl<C: BlockCodec> ContainerLog<C> {
+    fn stored_len(&self) -> u64 {
+        self.blocks.last().map_or(0, |block| block.log_offset + block.log_len)
+    }
+
+    /// Length of the log, including what hasn't been committed yet.
+    pub fn len(&self) -> u64 {
+        self.stored_len() + self.pending.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn blocks(&self) -> &[ContainerBlock] {
+        &self.blocks
+    }
+
+    fn block(&mut self, number: usize) -> Result<&[u8], Error> {
+        if self.cached.as_ref().is_none_or(|(cached, _)| *cached != number) {
+            let block = self.blocks[number];
+            let mut stored = vec![0; block.stored_len as usize];
+            self.file.seek(SeekFrom::Start(block.stored_offset))?;
+            self.file.read_exact(&mut stored)?;
+            let data = self.codec.open(block.log_offset, &stored)?;
+            if data.len() as u64 != block.log_len {
+                return Err(invalid("a block has the wrong length"));
+            }
+            self.cached = Some((number, data));
+        }
+        Ok(&self.cached.as_ref().expect("just cached").1)
+    }
+}
+
+impl<C: BlockCodec> Read for ContainerLog<C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let stored_len = self.stored_len();
+        let read = if self.position >= stored_len {
+            let start = ((self.position - stored_len) as usize).min(self.pending.len());
+            let read = buf.len().min(self.pending.len() - start);
+            buf[..read].copy_from_slice(&self.pending[start..start + read]);
+            read
+        } else {
+            let position = self.position;
+            let number = self.blocks.partition_point(|block| block.log_offset + block.log_len <= position);
+            let start = (position - self.blocks[number].log_offset) as usize;
+            let data = self.block(number).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            let read = buf.len().min(data.len() - start);
+            buf[..read].copy_from_slice(&data[start..start + read]);
+            read
+        };
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<C: BlockCodec> Seek for ContainerLog<C> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position"))?;
+        Ok(self.position)
+    }
+}
+
+impl<C: BlockCodec> Write for ContainerLog<C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.position != self.len() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "a container's log can only be appended to"));
+        }
+        self.pending.extend_from_slice(buf);
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A log and idx stored together in one file. Use [streams](#method.streams) to get at them with
+/// [FChatWriter](../struct.FChatWriter.html), [log](#method.log) to read them, and [commit](#method.commit) to store
+/// what was written.
+pub struct FChatContainer<C: BlockCodec> {
+    log: ContainerLog<C>,
+    idx: Cursor<Vec<u8>>,
+}
+
+impl<C: BlockCodec> FChatContainer<C> {
+    /// Start a container in `file`, which should be empty, for a conversation called `name`.
+    pub fn create(mut file: File, header: &[u8], codec: C, name: String) -> Result<Self, Error> {
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(header)?;
+        let mut idx = Cursor::new(Vec::new());
+        FChatIndex::new(name).write_header_to_buf(&mut idx)?;
+        let mut container = FChatContainer {
+            log: ContainerLog {
+                file,
+                codec,
+                blocks: Vec::new(),
+                data_start: header.len() as u64,
+                committed_len: header.len() as u64,
+                pending: Vec::new(),
+                position: 0,
+                cached: None,
+            },
+            idx,
+        };
+        container.commit()?;
+        Ok(container)
+    }
+
+    /// Open the container in `file`. The codec has to be the one it was created with. Whatever a commit that was cut
+    /// short left after the last whole footer is ignored, and written over by the next commit.
+    pub fn open(mut file: File, codec: C) -> Result<Self, Error> {
+        if file.seek(SeekFrom::End(0))? < FOOTER_LEN {
+            return Err(invalid("the file is too short"));
+        }
+        let (committed_len, table_offset, table_len) = last_footer(&mut file)?
+            .ok_or_else(|| invalid("the footer is missing or damaged"))?;
+        let mut stored = vec![0; table_len as usize];
+        file.seek(SeekFrom::Start(table_offset))?;
+        file.read_exact(&mut stored)?;
+        let table = codec.open(TABLE_CONTEXT, &stored)?;
+        let (data_start, idx, blocks) = read_table(&table, table_offset).map_err(|_| invalid("the block table is damaged"))?;
+        Ok(FChatContainer {
+            log: ContainerLog {
+                file,
+                codec,
+                blocks,
+                data_start,
+                committed_len,
+                pending: Vec::new(),
+                position: 0,
+                cached: None,
+            },
+            idx: Cursor::new(idx),
+        })
+    }
+
+    /// The log, positioned at its start.
+    pub fn log(&mut self) -> &mut ContainerLog<C> {
+        self.log.position = 0;
+        &mut self.log
+    }
+
+    /// The log and idx, both positioned at their start, e.g. for [FChatWriter::from_idx](../struct.FChatWriter.html#method.from_idx).
+    pub fn streams(&mut self) -> (&mut ContainerLog<C>, &mut Cursor<Vec<u8>>) {
+        self.log.position = 0;
+        self.idx.set_position(0);
+        (&mut self.log, &mut self.idx)
+    }
+
+    pub fn index(&self) -> Result<FChatIndex, Error> {
+        FChatIndex::from_buf(&mut Cursor::new(self.idx.get_ref()))
+    }
+
+    /// The committed blocks along with the date of the day each starts in. Blocks starting before the idx's first day
+    /// are left out.
+    pub fn block_dates(&self) -> Result<Vec<(NaiveDate, ContainerBlock)>, Error> {
+        let index = self.index()?;
+        Ok(self.log.blocks.iter().filter_map(|block| {
+            index.offsets.iter().take_while(|offset| offset.offset <= block.log_offset).last()
+                .map(|offset| (offset.date, *block))
+        }).collect())
+    }
+
+    /// A cursor at the first message of the last day on or before `date`, the same as
+    /// [from_index_offset](../struct.FChatMessageCursor.html#method.from_index_offset) on a plain log. Only the blocks
+    /// that get read are decoded. Returns `None` if the log starts after `date`.
+    pub fn cursor_at_date(&mut self, date: NaiveDate) -> Result<Option<FChatMessageCursor<'_>>, Error> {
+        let index = self.index()?;
+        match index.offsets.iter().take_while(|offset| offset.date <= date).last() {
+            Some(offset) => Ok(Some(FChatMessageCursor::from_index_offset(&mut self.log, offset)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Store everything written since the last commit. New log data is split into blocks where the idx says a day
+    /// starts, and if the last block is short or stops partway through a day it's sealed again along with the new data.
+    /// The new blocks, table and footer go after the last footer, so until the new footer is written the file still
+    /// opens as it was. If the commit fails, what was written stays pending. Once more of the file is taken up by
+    /// superseded blocks and tables than by live ones, the live ones are moved back to just after the header.
+    pub fn commit(&mut self) -> Result<(), Error> {
+        let mut starts: Vec<u64> = self.index().map(|index| index.offsets.iter().map(|offset| offset.offset).collect())
+            .unwrap_or_default();
+        let log = &mut self.log;
+        let stored_len = log.stored_len();
+        let end = log.len();
+        let block_size = log.codec.block_size();
+        let mut blocks = log.blocks.clone();
+        let reopen = !log.pending.is_empty() && blocks.last()
+            .is_some_and(|last| last.log_len < block_size || !starts.contains(&stored_len));
+        let (data_start, data) = if reopen {
+            let last = blocks.pop().expect("checked above");
+            let mut data = log.block(blocks.len())?.to_vec();
+            data.extend_from_slice(&log.pending);
+            (last.log_offset, Cow::Owned(data))
+        } else {
+            (stored_len, Cow::Borrowed(log.pending.as_slice()))
+        };
+        starts.retain(|start| *start > data_start && *start < end);
+        starts.push(end);
+        let mut block_start = data_start;
+        let mut stored_offset = log.committed_len;
+        log.file.seek(SeekFrom::Start(stored_offset))?;
+        for block_end in starts {
+            let block = &data[(block_start - data_start) as usize..(block_end - data_start) as usize];
+            if block.is_empty() || (block_end != end && (block.len() as u64) < block_size) {
+                continue;
+            }
+            let stored = log.codec.seal(block_start, block)?;
+            log.file.write_all(&stored)?;
+            blocks.push(ContainerBlock {
+                log_offset: block_start,
+                log_len: block.len() as u64,
+                stored_offset,
+                stored_len: stored.len() as u64,
+            });
+            stored_offset += stored.len() as u64;
+            block_start = block_end;
+        }
+        let table = self.seal_table(&blocks)?;
+        let committed_len = self.write_table(stored_offset, &table)?;
+        let log = &mut self.log;
+        log.blocks = blocks;
+        log.committed_len = committed_len;
+        log.pending.clear();
+        log.cached = None;
+        if self.garbage_len(table.len() as u64) > self.live_len(table.len() as u64) {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite the committed blocks and table right after the header, dropping the blocks and tables earlier commits
+    /// superseded. They're first copied after the last footer and committed there, so wherever this is cut short the
+    /// file still opens with everything that was committed.
+    fn compact(&mut self) -> Result<(), Error> {
+        let first_copy = self.log.committed_len;
+        for offset in [self.log.committed_len, self.log.data_start] {
+            let moved = moved_blocks(&self.log.blocks, offset);
+            let table = self.seal_table(&moved)?;
+            let table_offset = offset + moved.iter().map(|block| block.stored_len).sum::<u64>();
+            // The second copy is made from the first, whose footer stays the last in the file until the end is cut
+            // off, so it mustn't reach it.
+            if offset == self.log.data_start && table_offset + table.len() as u64 + FOOTER_LEN > first_copy {
+                break;
+            }
+            self.copy_blocks(&moved)?;
+            let committed_len = self.write_table(table_offset, &table)?;
+            self.log.blocks = moved;
+            self.log.committed_len = committed_len;
+        }
+        Ok(())
+    }
+
+    /// Copy the stored blocks as they are to where `moved` says they go.
+    fn copy_blocks(&mut self, moved: &[ContainerBlock]) -> Result<(), Error> {
+        let log = &mut self.log;
+        for (block, to) in log.blocks.iter().zip(moved) {
+            let mut stored = vec![0; block.stored_len as usize];
+            log.file.seek(SeekFrom::Start(block.stored_offset))?;
+            log.file.read_exact(&mut stored)?;
+            log.file.seek(SeekFrom::Start(to.stored_offset))?;
+            log.file.write_all(&stored)?;
+        }
+        Ok(())
+    }
+
+    fn seal_table(&self, blocks: &[ContainerBlock]) -> Result<Vec<u8>, Error> {
+        self.log.codec.seal(TABLE_CONTEXT, &write_table(self.log.data_start, self.idx.get_ref(), blocks)?)
+    }
+
+    /// Write `table` at `offset` followed by its footer, and cut the file off after it. Returns where the file ends.
+    fn write_table(&mut self, offset: u64, table: &[u8]) -> Result<u64, Error> {
+        let file = &mut self.log.file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(table)?;
+        // The footer only goes down once the blocks and table it points at are on disk.
+        file.sync_data()?;
+        file.write_u64::<LittleEndian>(offset)?;
+        file.write_u64::<LittleEndian>(table.len() as u64)?;
+        file.write_all(FOOTER_MAGIC)?;
+        let committed_len = file.stream_position()?;
+        file.set_len(committed_len)?;
+        file.sync_all()?;
+        Ok(committed_len)
+    }
+
+    /// How much of the file the committed blocks and a table `table_len` long take up.
+    fn live_len(&self, table_len: u64) -> u64 {
+        self.log.blocks.iter().map(|block| block.stored_len).sum::<u64>() + table_len + FOOTER_LEN
+    }
+
+    /// How much of the file after the header is taken up by superseded blocks and tables.
+    fn garbage_len(&self, table_len: u64) -> u64 {
+        (self.log.committed_len - self.log.data_start).saturating_sub(self.live_len(table_len))
+    }
+
+    /// Copy a plain log and idx into the container, which has to be empty, and commit them.
+    pub fn import<L: Read, I: Read>(&mut self, mut log: L, mut idx: I) -> Result<(), Error> {
+        if !self.log.is_empty() {
+            return Err(invalid("only an empty container can be imported into"));
+        }
+        io::copy(&mut log, &mut self.log.pending)?;
+        let mut idx_bytes = Vec::new();
+        idx.read_to_end(&mut idx_bytes)?;
+        self.idx = Cursor::new(idx_bytes);
+        self.commit()
+    }
+
+    /// Write the log and idx out as plain files.
+    pub fn export<L: Write, I: Write>(&mut self, mut log: L, mut idx: I) -> Result<(), Error> {
+        io::copy(self.log(), &mut log)?;
+        idx.write_all(self.idx.get_ref())?;
+        Ok(())
+    }
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::from(InvalidContainer { reason: reason.to_string() })
+}
+
+/// Where the last whole footer in `file` ends, along with where the table it points to starts and how long it is. A
+/// footer is whole if it has the magic and the table it points to ends right before it.
+fn last_footer(file: &mut File) -> Result<Option<(u64, u64, u64)>, Error> {
+    let mut window_end = file.seek(SeekFrom::End(0))?;
+    while window_end >= FOOTER_LEN {
+        let window_start = window_end.saturating_sub(FOOTER_SCAN_LEN);
+        let mut window = vec![0; (window_end - window_start) as usize];
+        file.seek(SeekFrom::Start(window_start))?;
+        file.read_exact(&mut window)?;
+        for footer_start in (0..=window.len() - FOOTER_LEN as usize).rev() {
+            let mut footer = &window[footer_start..footer_start + FOOTER_LEN as usize];
+            let table_offset = footer.read_u64::<LittleEndian>()?;
+            let table_len = footer.read_u64::<LittleEndian>()?;
+            let footer_start = window_start + footer_start as u64;
+            if footer == FOOTER_MAGIC && table_offset.checked_add(table_len) == Some(footer_start) {
+                return Ok(Some((footer_start + FOOTER_LEN, table_offset, table_len)));
+            }
+        }
+        if window_start == 0 {
+            break;
+        }
+        // Overlap the windows so a footer split between them is found.
+        window_end = window_start + FOOTER_LEN - 1;
+    }
+    Ok(None)
+}
+
+/// `blocks` stored one after the other from `offset`.
+fn moved_blocks(blocks: &[ContainerBlock], mut offset: u64) -> Vec<ContainerBlock> {
+    blocks.iter().map(|block| {
+        let moved = ContainerBlock { stored_offset: offset, ..*block };
+        offset += block.stored_len;
+        moved
+    }).collect()
+}
+
+fn write_table(data_start: u64, idx: &[u8], blocks: &[ContainerBlock]) -> Result<Vec<u8>, Error> {
+    let mut table = Vec::new();
+    table.write_u64::<LittleEndian>(data_start)?;
+    table.write_u64::<LittleEndian>(idx.len() as u64)?;
+    table.write_all(idx)?;
+    table.write_u64::<LittleEndian>(blocks.len() as u64)?;
+    for block in blocks {
+        table.write_u64::<LittleEndian>(block.log_offset)?;
+        table.write_u64::<LittleEndian>(block.log_len)?;
+        table.write_u64::<LittleEndian>(block.stored_offset)?;
+        table.write_u64::<LittleEndian>(block.stored_len)?;
+    }
+    Ok(table)
+}
+
+/// Read a table that was stored at `table_offset`. Blocks have to lie between the header and the table.
+fn read_table(mut table: &[u8], table_offset: u64) -> io::Result<(u64, Vec<u8>, Vec<ContainerBlock>)> {
+    let data_start = table.read_u64::<LittleEndian>()?;
+    if data_start > table_offset {
+        return Err(io::Error::from(io::ErrorKind::InvalidData));
+    }
+    let idx_len = table.read_u64::<LittleEndian>()?;
+    if idx_len > table.len() as u64 {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    let mut idx = vec![0; idx_len as usize];
+    table.read_exact(&mut idx)?;
+    let count = table.read_u64::<LittleEndian>()?;
+    let mut blocks = Vec::new();
+    let mut log_offset: u64 = 0;
+    for _ in 0..count {
+        let block = ContainerBlock {
+            log_offset: table.read_u64::<LittleEndian>()?,
+            log_len: table.read_u64::<LittleEndian>()?,
+            stored_offset: table.read_u64::<LittleEndian>()?,
+            stored_len: table.read_u64::<LittleEndian>()?,
+        };
+        // Blocks have to cover the log without gaps, which reading relies on.
+        let stored_end = block.stored_offset.checked_add(block.stored_len);
+        if block.log_offset != log_offset || block.log_len == 0 || log_offset.checked_add(block.log_len).is_none()
+            || block.stored_offset < data_start || stored_end.is_none_or(|end| end > table_offset) {
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+        }
+        log_offset += block.log_len;
+        blocks.push(block);
+    }
+    Ok((data_start, idx, blocks))
+}
@@ -0,0 +1,113 @@
+//! Removing the runs of duplicated records that bugs in old clients and sync tools left in some logs.
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read};
+use std::path::Path;
+use chrono::TimeDelta;
+use crate::error::Error;
+use crate::fchat_index::FChatIndex;
+use crate::fchat_message::{FChatMessage, STRUCTURAL_READ};
+use crate::{FChatMessageReader, FChatWriter};
+
+/// How many of the messages before one are looked at for the one it duplicates, by default.
+pub const DEFAULT_LOOKBACK: usize = 8;
+
+/// What [dedup_files](struct.FChatDeduplicator.html#method.dedup_files) removed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FChatDedupReport {
+    pub messages_kept: u64,
+    pub messages_removed: u64,
+    pub bytes_removed: u64,
+}
+
+/// Spots messages that repeat one of the few before them, with the same sender, type and text and a timestamp within
+/// a window of it.
+pub struct FChatDeduplicator {
+    /// How far apart two identical messages can be and still be one message duplicated. Zero only catches copies
+    /// with the same timestamp.
+    pub window: TimeDelta,
+    /// How many of the messages kept before a message are looked at for the one it duplicates.
+    pub lookback: usize,
+    recent: VecDeque<FChatMessage>,
+}
+
+impl FChatDeduplicator {
+    pub fn new(window: TimeDelta) -> Self {
+        FChatDeduplicator { window, lookback: DEFAULT_LOOKBACK, recent: VecDeque::new() }
+    }
+
+    /// Whether `message` duplicates one of the messages kept before it. Messages that don't are kept.
+    pub fn is_duplicate(&mut self, message: &FChatMessage) -> bool {
+        let duplicate = self.recent.iter().any(|seen| {
+            (seen.datetime - message.datetime).abs() <= self.window
+                && seen.sender == message.sender
+                && seen.body.kind() == message.body.kind()
+                && seen.body.text() == message.body.text()
+        });
+        if !duplicate {
+            self.recent.push_back(message.clone());
+            while self.recent.len() > self.lookback {
+                self.recent.pop_front();
+            }
+        }
+        duplicate
+    }
+
+    /// Rewrite the log at `log_path` without its duplicates and regenerate its idx at `idx_path`. The records kept are
+    /// copied as they are, and the idx keeps its name, or is named after the log if it can't be read. Both are written
+    /// next to the originals first and then replace them, and are left alone if there's nothing to remove or something
+    /// goes wrong. Messages from logs deduplicated before aren't looked at. Messages
+    /// [decoded lossily](../struct.FChatMessageReader.html#method.lossy) are always kept, since their text can't be
+    /// compared for sure.
+    pub fn dedup_files<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, log_path: P, idx_path: Q) -> Result<FChatDedupReport, Error> {
+        let (log_path, idx_path) = (log_path.as_ref(), idx_path.as_ref());
+        let file_name = log_path.file_name().map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+        let name = File::open(idx_path).ok()
+            .and_then(|idx| FChatIndex::read_header_from_buf(&mut BufReader::new(idx)).ok())
+            .map_or_else(|| file_name.clone(), |index| index.name);
+        let log_tmp = log_path.with_file_name(format!(".{}.dedup", file_name));
+        let idx_tmp = idx_path.with_file_name(format!(".{}.idx.dedup", file_name));
+        self.recent.clear();
+        let result = self.dedup_into(log_path, idx_path, &log_tmp, &idx_tmp, name);
+        if result.is_err() {
+            let _ = fs::remove_file(&log_tmp);
+            let _ = fs::remove_file(&idx_tmp);
+        }
+        result
+    }
+
+    fn dedup_into(&mut self, log_path: &Path, idx_path: &Path, log_tmp: &Path, idx_tmp: &Path, name: String) -> Result<FChatDedupReport, Error> {
+        let mut report = FChatDedupReport::default();
+        let mut records = BufReader::new(File::open(log_path).map_err(|err| Error::from(err).in_file(log_path))?);
+        let mut out = BufWriter::new(File::create(log_tmp).map_err(|err| Error::from(err).in_file(log_tmp))?);
+        let mut reader = FChatMessageReader::from_path(log_path)?.with_options(STRUCTURAL_READ);
+        let mut start = 0;
+        while let Some(message) = reader.next() {
+            let message = message?;
+            let record_len = reader.position() - start;
+            start = reader.position();
+            if !reader.lossy() && self.is_duplicate(&message) {
+                records.seek_relative(record_len as i64)?;
+                report.messages_removed += 1;
+                report.bytes_removed += record_len;
+            } else {
+                io::copy(&mut (&mut records).take(record_len), &mut out)?;
+                report.messages_kept += 1;
+            }
+        }
+        out.into_inner().map_err(|err| Error::from(err.into_error()))?.sync_all()?;
+        if report.messages_removed == 0 {
+            fs::remove_file(log_tmp).map_err(|err| Error::from(err).in_file(log_tmp))?;
+            return Ok(report);
+        }
+
+        let log = OpenOptions::new().read(true).write(true).open(log_tmp).map_err(|err| Error::from(err).in_file(log_tmp))?;
+        let idx = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(idx_tmp)
+            .map_err(|err| Error::from(err).in_file(idx_tmp))?;
+        FChatWriter::from_log(&log, &idx, name).map_err(|err| err.in_file(log_tmp))?;
+        idx.sync_all()?;
+        fs::rename(log_tmp, log_path).map_err(|err| Error::from(err).in_file(log_path))?;
+        fs::rename(idx_tmp, idx_path).map_err(|err| Error::from(err).in_file(idx_path))?;
+        Ok(report)
+    }
+}
@@ -0,0 +1,189 @@
+//! Comparing two copies of a conversation's log, for when they've drifted apart and it isn't clear how.
+//!
+//! The logs are lined up by timestamp. Messages sent in the same second are matched up by content, so only what
+//! actually differs is reported, either as a message one copy has and the other doesn't, or as a message both have
+//! at that time but with a different sender, type or text. A message whose timestamp changed shows up as missing
+//! from one copy and added to the other.
+use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use chrono::NaiveDateTime;
+use crate::error::Error;
+use crate::fchat_message::{FChatMessage, FChatMessageKind, STRUCTURAL_READ};
+use crate::FChatMessageCursor;
+
+/// A message as the diff reports it, with where its record starts in the log it came from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DiffedMessage {
+    pub offset: u64,
+    pub datetime: NaiveDateTime,
+    pub sender: String,
+    pub kind: FChatMessageKind,
+    pub text: String,
+}
+
+impl DiffedMessage {
+    pub fn new(offset: u64, message: &FChatMessage) -> Self {
+        DiffedMessage {
+            offset,
+            datetime: message.datetime,
+            sender: message.sender.clone(),
+            kind: message.body.kind(),
+            text: message.body.text().to_string(),
+        }
+    }
+
+    /// Whether the two are the same message, wherever they are in their logs.
+    pub fn same_as(&self, other: &DiffedMessage) -> bool {
+        self.datetime == other.datetime && self.sender == other.sender && self.kind == other.kind && self.text == other.text
+    }
+}
+
+impl Display for DiffedMessage {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} [{}] {}: {}", self.datetime, self.kind, self.sender, self.text)
+    }
+}
+
+/// One way the two logs differ.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "difference", rename_all = "snake_case"))]
+pub enum LogDifference {
+    OnlyInA(DiffedMessage),
+    OnlyInB(DiffedMessage),
+    /// Both logs have a message at this time, but with a different sender, type or text.
+    Changed { a: DiffedMessage, b: DiffedMessage },
+}
+
+/// How two logs, A and B, differ. Shown with `Display`, it reads like a diff: `-` for messages only in A, `+` for
+/// messages only in B, and `<` and `>` for the two sides of a changed message.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LogDiff {
+    /// Messages both logs have.
+    pub same: u64,
+    /// In timestamp order.
+    pub differences: Vec<LogDifference>,
+}
+
+type NextMessage<'a> = dyn FnMut() -> Result<Option<DiffedMessage>, Error> + 'a;
+
+impl LogDiff {
+    /// Compare the logs from each cursor's position to their ends.
+    pub fn from_cursors(a: &mut FChatMessageCursor, b: &mut FChatMessageCursor) -> Result<Self, Error> {
+        Self::compare(&mut || next(a), &mut || next(b))
+    }
+
+    /// Compare the logs at the two paths. Unknown types and text that isn't valid UTF-8 are compared as well.
+    pub fn from_files<P: AsRef<Path>, Q: AsRef<Path>>(a_path: P, b_path: Q) -> Result<Self, Error> {
+        let open = |path: &Path| -> Result<FChatMessageCursor<'static>, Error> {
+            let log = File::open(path).map_err(|err| Error::from(err).in_file(path))?;
+            Ok(FChatMessageCursor::new(BufReader::new(log)).map_err(|err| err.in_file(path))?.with_options(STRUCTURAL_READ))
+        };
+        let (a_path, b_path) = (a_path.as_ref(), b_path.as_ref());
+        let (mut a, mut b) = (open(a_path)?, open(b_path)?);
+        Self::compare(
+            &mut || next(&mut a).map_err(|err| err.in_file(a_path)),
+            &mut || next(&mut b).map_err(|err| err.in_file(b_path)),
+        )
+    }
+
+    fn compare(next_a: &mut NextMessage, next_b: &mut NextMessage) -> Result<Self, Error> {
+        let mut diff = LogDiff::default();
+        let (mut a, mut b) = (next_a()?, next_b()?);
+        loop {
+            let datetime = match (&a, &b) {
+                (Some(a), Some(b)) => a.datetime.min(b.datetime),
+                (Some(message), None) | (None, Some(message)) => message.datetime,
+                (None, None) => break,
+            };
+            let mut group_a = Vec::new();
+            while let Some(message) = a.take_if(|message| message.datetime == datetime) {
+                group_a.push(message);
+                a = next_a()?;
+            }
+            let mut group_b = Vec::new();
+            while let Some(message) = b.take_if(|message| message.datetime == datetime) {
+                group_b.push(message);
+                b = next_b()?;
+            }
+            diff.add_second(group_a, group_b);
+        }
+        Ok(diff)
+    }
+
+    /// Match up the messages each log has in one second: identical ones first, then what's left of A with what's
+    /// left of B, preferring the same sender.
+    fn add_second(&mut self, a: Vec<DiffedMessage>, b: Vec<DiffedMessage>) {
+        let mut b: Vec<Option<DiffedMessage>> = b.into_iter().map(Some).collect();
+        let mut unmatched = Vec::new();
+        for message in a {
+            match b.iter_mut().find(|other| other.as_ref().is_some_and(|other| other.same_as(&message))) {
+                Some(other) => {
+                    *other = None;
+                    self.same += 1;
+                }
+                None => unmatched.push(message),
+            }
+        }
+        for message in unmatched {
+            let paired = b.iter().position(|other| other.as_ref().is_some_and(|other| other.sender == message.sender))
+                .or_else(|| b.iter().position(Option::is_some));
+            self.differences.push(match paired.and_then(|n| b[n].take()) {
+                Some(other) => LogDifference::Changed { a: message, b: other },
+                None => LogDifference::OnlyInA(message),
+            });
+        }
+        self.differences.extend(b.into_iter().flatten().map(LogDifference::OnlyInB));
+    }
+
+    /// Whether the two logs have the same messages.
+    pub fn is_empty(&self) -> bool {
+        self.differences.is_empty()
+    }
+
+    pub fn only_in_a(&self) -> impl Iterator<Item = &DiffedMessage> {
+        self.differences.iter().filter_map(|difference| match difference {
+            LogDifference::OnlyInA(message) => Some(message),
+            _ => None,
+        })
+    }
+
+    pub fn only_in_b(&self) -> impl Iterator<Item = &DiffedMessage> {
+        self.differences.iter().filter_map(|difference| match difference {
+            LogDifference::OnlyInB(message) => Some(message),
+            _ => None,
+        })
+    }
+
+    pub fn changed(&self) -> impl Iterator<Item = (&DiffedMessage, &DiffedMessage)> {
+        self.differences.iter().filter_map(|difference| match difference {
+            LogDifference::Changed { a, b } => Some((a, b)),
+            _ => None,
+        })
+    }
+}
+
+impl Display for LogDiff {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for difference in &self.differences {
+            match difference {
+                LogDifference::OnlyInA(message) => writeln!(f, "- {}", message)?,
+                LogDifference::OnlyInB(message) => writeln!(f, "+ {}", message)?,
+                LogDifference::Changed { a, b } => writeln!(f, "< {}\n> {}", a, b)?,
+            }
+        }
+        write!(
+            f,
+            "{} the same, {} only in A, {} only in B, {} changed",
+            self.same, self.only_in_a().count(), self.only_in_b().count(), self.changed().count(),
+        )
+    }
+}
+
+fn next(cursor: &mut FChatMessageCursor) -> Result<Option<DiffedMessage>, Error> {
+    Ok(cursor.next_message().transpose()?.map(|(offset, message)| DiffedMessage::new(offset, &message)))
+}
@@ -0,0 +1,170 @@
+//! [Containers](../container/struct.FChatContainer.html) encrypted with a passphrase, for keeping logs on machines other
+//! people can get at. Blocks and the table are sealed with XChaCha20-Poly1305 under a key derived from the passphrase
+//! with Argon2id, so the messages, the idx and even which days had activity are hidden, and no block or table can be
+//! altered or moved unnoticed. Each commit leaves the ones before it in the file, though, so someone who can write to
+//! it can roll the container back to an earlier commit by cutting it short or appending an old table again.
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+use std::path::Path;
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crate::container::{BlockCodec, FChatContainer};
+use crate::error::{Error, InvalidContainer};
+
+const MAGIC: &[u8; 8] = b"FCHATENC";
+const VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+/// The most memory, in KiB, a container's header can ask key derivation to use: 4 GiB.
+pub const MAX_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+/// The most passes a container's header can ask key derivation to make.
+pub const MAX_ITERATIONS: u32 = 64;
+/// The most lanes a container's header can ask key derivation to use.
+pub const MAX_PARALLELISM: u32 = 64;
+
+/// How hard turning the passphrase into a key is. The defaults are Argon2's own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FChatKeyDerivation {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for FChatKeyDerivation {
+    fn default() -> Self {
+        FChatKeyDerivation {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+/// Seals blocks with a key derived from a passphrase. Every block gets a random nonce, stored in front of it, and is
+/// bound to the container's header and its place in the log.
+pub struct EncryptionCodec {
+    cipher: XChaCha20Poly1305,
+    header: Vec<u8>,
+}
+
+impl FChatKeyDerivation {
+    /// Check the parameters are within the limits, so a damaged or hostile header can't make opening a container
+    /// take all the memory or time there is.
+    pub fn check(&self) -> Result<(), Error> {
+        if self.memory_kib > MAX_MEMORY_KIB || self.iterations > MAX_ITERATIONS || self.parallelism > MAX_PARALLELISM {
+            return Err(invalid(&format!(
+                "key derivation asks for {} KiB, {} iterations and {} lanes, more than the {} KiB, {} iterations and {} lanes allowed",
+                self.memory_kib, self.iterations, self.parallelism, MAX_MEMORY_KIB, MAX_ITERATIONS, MAX_PARALLELISM,
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl EncryptionCodec {
+    fn new(passphrase: &[u8], derivation: FChatKeyDerivation, salt: &[u8], header: Vec<u8>) -> Result<Self, Error> {
+        derivation.check()?;
+        let params = Params::new(derivation.memory_kib, derivation.iterations, derivation.parallelism, Some(32))
+            .map_err(|err| invalid(&format!("bad key derivation parameters: {}", err)))?;
+        let mut key = [0; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase, salt, &mut key)
+            .map_err(|err| invalid(&format!("the key couldn't be derived: {}", err)))?;
+        Ok(EncryptionCodec {
+            cipher: XChaCha20Poly1305::new(&key.into()),
+            header,
+        })
+    }
+
+    fn associated_data(&self, context: u64) -> Vec<u8> {
+        let mut aad = self.header.clone();
+        aad.extend_from_slice(&context.to_le_bytes());
+        aad
+    }
+}
+
+impl BlockCodec for EncryptionCodec {
+    fn seal(&self, context: u64, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = self.associated_data(context);
+        let ciphertext = self.cipher.encrypt(&nonce, Payload { msg: data, aad: &aad })
+            .map_err(|_| invalid("a block couldn't be encrypted"))?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    fn open(&self, context: u64, data: &[u8]) -> Result<Vec<u8>, Error> {
+        if data.len() < NONCE_LEN {
+            return Err(invalid("a block is too short"));
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let aad = self.associated_data(context);
+        self.cipher.decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad: &aad })
+            .map_err(|_| invalid("the passphrase is wrong or the container was tampered with"))
+    }
+}
+
+/// A log and idx encrypted with a passphrase.
+pub type FChatEncryptedLog = FChatContainer<EncryptionCodec>;
+
+impl FChatContainer<EncryptionCodec> {
+    /// Create an encrypted container at `path` for a conversation called `name`, replacing anything already there.
+    pub fn create_encrypted<P: AsRef<Path>>(path: P, passphrase: &[u8], name: String, derivation: FChatKeyDerivation) -> Result<Self, Error> {
+        let mut salt = [0; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let header = write_header(derivation, &salt)?;
+        let codec = EncryptionCodec::new(passphrase, derivation, &salt, header.clone())?;
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path)
+            .map_err(|err| Error::from(err).in_file(&path))?;
+        Self::create(file, &header, codec, name).map_err(|err| err.in_file(&path))
+    }
+
+    /// Open the encrypted container at `path`. A wrong passphrase is an
+    /// [InvalidContainerError](../error/enum.Error.html#variant.InvalidContainerError).
+    pub fn open_encrypted<P: AsRef<Path>>(path: P, passphrase: &[u8]) -> Result<Self, Error> {
+        let mut file = OpenOptions::new().read(true).write(true).open(&path)
+            .map_err(|err| Error::from(err).in_file(&path))?;
+        let codec = read_header(&mut file, passphrase).map_err(|err| err.in_file(&path))?;
+        Self::open(file, codec).map_err(|err| err.in_file(&path))
+    }
+}
+
+fn write_header(derivation: FChatKeyDerivation, salt: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut header = Vec::new();
+    header.extend_from_slice(MAGIC);
+    header.write_u8(VERSION)?;
+    header.write_u32::<LittleEndian>(derivation.memory_kib)?;
+    header.write_u32::<LittleEndian>(derivation.iterations)?;
+    header.write_u32::<LittleEndian>(derivation.parallelism)?;
+    header.extend_from_slice(salt);
+    Ok(header)
+}
+
+fn read_header(file: &mut File, passphrase: &[u8]) -> Result<EncryptionCodec, Error> {
+    let mut magic = [0; 8];
+    file.read_exact(&mut magic).map_err(|_| invalid("not an encrypted log"))?;
+    if &magic != MAGIC {
+        return Err(invalid("not an encrypted log"));
+    }
+    let version = file.read_u8()?;
+    if version != VERSION {
+        return Err(invalid(&format!("unsupported version {}", version)));
+    }
+    let derivation = FChatKeyDerivation {
+        memory_kib: file.read_u32::<LittleEndian>()?,
+        iterations: file.read_u32::<LittleEndian>()?,
+        parallelism: file.read_u32::<LittleEndian>()?,
+    };
+    let mut salt = [0; SALT_LEN];
+    file.read_exact(&mut salt)?;
+    let header = write_header(derivation, &salt)?;
+    EncryptionCodec::new(passphrase, derivation, &salt, header)
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::from(InvalidContainer { reason: reason.to_string() })
+}
@@ -1,4 +1,7 @@
 use crate::fchat_message::FChatMessage;
+use chrono::NaiveDateTime;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::error;
 use std::fmt;
 use std::fmt::{Debug, Display, Formatter};
@@ -91,10 +94,13 @@ impl error::Error for ConformanceError {
     }
 }
 
+/// Never returned by anything in this crate, and kept only so code matching on it still compiles.
+#[deprecated(note = "nothing returns this error; missing information is reported as a ConformanceError")]
 pub struct InadequateInformation {
     pub reason: String
 }
 
+#[allow(deprecated)]
 impl Display for InadequateInformation {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         write!(
@@ -105,6 +111,7 @@ impl Display for InadequateInformation {
     }
 }
 
+#[allow(deprecated)]
 impl Debug for InadequateInformation {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         write!(
@@ -115,27 +122,331 @@ impl Debug for InadequateInformation {
     }
 }
 
+#[allow(deprecated)]
 impl error::Error for InadequateInformation {
     fn description(&self) -> &str {
         "More information is needed to operate."
     }
 }
 
+pub struct OutOfOrderMessage {
+    pub message: FChatMessage,
+    pub last_datetime: NaiveDateTime,
+}
+
+impl Display for OutOfOrderMessage {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "The message is dated {}, which is before the last message in the log ({})",
+            self.message.datetime, self.last_datetime
+        )
+    }
+}
+
+impl Debug for OutOfOrderMessage {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "OutOfOrderMessage {{ datetime: {}, last_datetime: {} }}",
+            self.message.datetime, self.last_datetime
+        )
+    }
+}
+
+impl error::Error for OutOfOrderMessage {
+    fn description(&self) -> &str {
+        "The message is older than the last message in the log."
+    }
+}
+
+pub struct InvalidSender {
+    pub sender: String,
+    pub reason: String,
+}
+
+impl Display for InvalidSender {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "The sender \"{}\" can't be written to a log because {}",
+            self.sender, self.reason
+        )
+    }
+}
+
+impl Debug for InvalidSender {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "InvalidSender {{ sender: {}, reason: {} }}",
+            self.sender, self.reason
+        )
+    }
+}
+
+impl error::Error for InvalidSender {
+    fn description(&self) -> &str {
+        "The sender can't be written to a log."
+    }
+}
+
+pub struct MessageTooLong {
+    pub length: usize,
+    pub max: usize,
+}
+
+impl Display for MessageTooLong {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "The message is {} bytes long, but a record can only hold {}",
+            self.length, self.max
+        )
+    }
+}
+
+impl Debug for MessageTooLong {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "MessageTooLong {{ length: {}, max: {} }}",
+            self.length, self.max
+        )
+    }
+}
+
+impl error::Error for MessageTooLong {
+    fn description(&self) -> &str {
+        "The message is too long to fit in a record."
+    }
+}
+
+/// A container file that isn't one, is damaged, or can't be opened with the key it was given.
+pub struct InvalidContainer {
+    pub reason: String,
+}
+
+impl Display for InvalidContainer {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "The container can't be read: {}", self.reason)
+    }
+}
+
+impl Debug for InvalidContainer {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "InvalidContainer {{ reason: {} }}", self.reason)
+    }
+}
+
+impl error::Error for InvalidContainer {
+    fn description(&self) -> &str {
+        "The container can't be read."
+    }
+}
+
+/// An archive that isn't one, is damaged, or doesn't have the file that was asked for.
+pub struct InvalidArchive {
+    pub reason: String,
+}
+
+impl Display for InvalidArchive {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "The archive can't be read: {}", self.reason)
+    }
+}
+
+impl Debug for InvalidArchive {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "InvalidArchive {{ reason: {} }}", self.reason)
+    }
+}
+
+impl error::Error for InvalidArchive {
+    fn description(&self) -> &str {
+        "The archive can't be read."
+    }
+}
+
+/// A name that can't be an F-List character's.
+pub struct InvalidName {
+    pub name: String,
+    pub reason: String,
+}
+
+impl Display for InvalidName {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "\"{}\" isn't a valid character name because {}", self.name, self.reason)
+    }
+}
+
+impl Debug for InvalidName {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "InvalidName {{ name: {}, reason: {} }}", self.name, self.reason)
+    }
+}
+
+impl error::Error for InvalidName {
+    fn description(&self) -> &str {
+        "The name isn't a valid character name."
+    }
+}
+
+/// An error along with where in which file it happened, as far as that is known.
+pub struct LocatedError {
+    pub error: Box<Error>,
+    /// Byte offset of the record or idx entry that couldn't be read.
+    pub offset: Option<u64>,
+    /// How many records or idx entries came before the one that couldn't be read.
+    pub record: Option<u64>,
+    pub path: Option<PathBuf>,
+}
+
+impl Display for LocatedError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.error)?;
+        if let Some(offset) = self.offset {
+            write!(f, " (at byte {}", offset)?;
+            if let Some(record) = self.record {
+                write!(f, ", record {}", record)?;
+            }
+            write!(f, ")")?;
+        }
+        if let Some(path) = &self.path {
+            write!(f, " in {}", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for LocatedError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "LocatedError {{ error: {:?}, offset: {:?}, record: {:?}, path: {:?} }}",
+            self.error, self.offset, self.record, self.path
+        )
+    }
+}
+
+impl error::Error for LocatedError {
+    /// The message already includes the located error's, so this skips straight to what caused that.
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+#[allow(deprecated)]
 #[derive(Debug)]
 pub enum Error {
     IOError(std::io::Error),
+    /// The end of the log was reached cleanly, right where a record would start.
     EOF(std::io::Error),
+    /// The stream ended partway through a record or idx entry.
+    TruncatedError(std::io::Error),
+    LocatedError(LocatedError),
     ConversionError(std::num::TryFromIntError),
     MessageLengthError(BadMessageLength),
     UTF8ConversionError(std::string::FromUtf8Error),
     UnknownMessageTypeError(UnknownMessageType),
     ConformanceError(ConformanceError),
-    InadequateInformation(InadequateInformation)
+    #[deprecated(note = "nothing returns this error; missing information is reported as a ConformanceError")]
+    InadequateInformation(InadequateInformation),
+    OutOfOrderError(OutOfOrderMessage),
+    InvalidSenderError(InvalidSender),
+    MessageTooLongError(MessageTooLong),
+    InvalidContainerError(InvalidContainer),
+    InvalidArchiveError(InvalidArchive),
+    InvalidNameError(InvalidName),
 }
 
+impl Error {
+    /// Attach the byte offset and record ordinal an error happened at, unless it already has them.
+    pub fn at(self, offset: u64, record: Option<u64>) -> Self {
+        match self {
+            Self::LocatedError(mut located) => {
+                located.offset = located.offset.or(Some(offset));
+                located.record = located.record.or(record);
+                Self::LocatedError(located)
+            }
+            error => Self::LocatedError(LocatedError {
+                error: Box::new(error),
+                offset: Some(offset),
+                record,
+                path: None,
+            }),
+        }
+    }
+
+    /// Attach the path of the file an error happened in, unless it already has one.
+    pub fn in_file<P: AsRef<Path>>(self, path: P) -> Self {
+        match self {
+            Self::LocatedError(mut located) => {
+                if located.path.is_none() {
+                    located.path = Some(path.as_ref().to_path_buf());
+                }
+                Self::LocatedError(located)
+            }
+            error => Self::LocatedError(LocatedError {
+                error: Box::new(error),
+                offset: None,
+                record: None,
+                path: Some(path.as_ref().to_path_buf()),
+            }),
+        }
+    }
+
+    /// The error without any location attached to it.
+    pub fn root(&self) -> &Error {
+        match self {
+            Self::LocatedError(located) => located.error.root(),
+            error => error,
+        }
+    }
+
+    pub fn offset(&self) -> Option<u64> {
+        match self {
+            Self::LocatedError(located) => located.offset,
+            _ => None,
+        }
+    }
+
+    pub fn record(&self) -> Option<u64> {
+        match self {
+            Self::LocatedError(located) => located.record,
+            _ => None,
+        }
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Self::LocatedError(located) => located.path.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+#[allow(deprecated)]
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Self::IOError(err) => write!(f, "{}", err),
+            Self::EOF(_) => write!(f, "Reached the end of the file"),
+            Self::TruncatedError(_) => write!(f, "The file ends partway through a record"),
+            Self::LocatedError(err) => write!(f, "{}", err),
+            Self::ConversionError(err) => write!(f, "A number didn't fit the size it's stored as: {}", err),
+            Self::MessageLengthError(err) => write!(f, "{}", err),
+            Self::UTF8ConversionError(err) => write!(f, "Text isn't valid UTF-8: {}", err),
+            Self::UnknownMessageTypeError(err) => write!(f, "{}", err),
+            Self::ConformanceError(err) => write!(f, "{}", err),
+            Self::InadequateInformation(err) => write!(f, "{}", err),
+            Self::OutOfOrderError(err) => write!(f, "{}", err),
+            Self::InvalidSenderError(err) => write!(f, "{}", err),
+            Self::MessageTooLongError(err) => write!(f, "{}", err),
+            Self::InvalidContainerError(err) => write!(f, "{}", err),
+            Self::InvalidArchiveError(err) => write!(f, "{}", err),
+            Self::InvalidNameError(err) => write!(f, "{}", err),
+        }
     }
 }
 
@@ -143,6 +454,43 @@ impl error::Error for Error {
     fn description(&self) -> &str {
         "failed to write or read message"
     }
+
+    /// Only the errors whose message doesn't already include their cause's have one, so printing the chain doesn't
+    /// repeat anything.
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::EOF(err) | Self::TruncatedError(err) => Some(err),
+            Self::LocatedError(err) => err.source(),
+            _ => None,
+        }
+    }
+}
+
+/// Turn an error from reading partway into a record into a [TruncatedError](enum.Error.html#variant.TruncatedError) if the stream ran out.
+pub(crate) fn truncated(err: std::io::Error) -> Error {
+    if err.kind() == std::io::ErrorKind::UnexpectedEof {
+        Error::TruncatedError(err)
+    } else {
+        Error::IOError(err)
+    }
+}
+
+/// Fill `bytes` from the start of a record. Running out before anything was read is a clean [EOF](enum.Error.html#variant.EOF),
+/// running out partway is a [TruncatedError](enum.Error.html#variant.TruncatedError).
+pub(crate) fn read_record_start<R: Read + ?Sized>(buffer: &mut R, bytes: &mut [u8]) -> Result<(), Error> {
+    let mut filled = 0;
+    while filled < bytes.len() {
+        match buffer.read(&mut bytes[filled..]) {
+            Ok(0) => {
+                let err = std::io::Error::from(std::io::ErrorKind::UnexpectedEof);
+                return Err(if filled == 0 { Error::EOF(err) } else { Error::TruncatedError(err) });
+            }
+            Ok(read) => { filled += read; }
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => { return Err(Error::IOError(err)); }
+        }
+    }
+    Ok(())
 }
 
 impl From<std::io::Error> for Error {
@@ -177,4 +525,40 @@ impl From<UnknownMessageType> for Error {
     fn from(item: UnknownMessageType) -> Self {
         Self::UnknownMessageTypeError(item)
     }
-}
\ No newline at end of file
+}
+
+impl From<OutOfOrderMessage> for Error {
+    fn from(item: OutOfOrderMessage) -> Self {
+        Self::OutOfOrderError(item)
+    }
+}
+
+impl From<InvalidSender> for Error {
+    fn from(item: InvalidSender) -> Self {
+        Self::InvalidSenderError(item)
+    }
+}
+
+impl From<MessageTooLong> for Error {
+    fn from(item: MessageTooLong) -> Self {
+        Self::MessageTooLongError(item)
+    }
+}
+
+impl From<InvalidContainer> for Error {
+    fn from(item: InvalidContainer) -> Self {
+        Self::InvalidContainerError(item)
+    }
+}
+
+impl From<InvalidArchive> for Error {
+    fn from(item: InvalidArchive) -> Self {
+        Self::InvalidArchiveError(item)
+    }
+}
+
+impl From<InvalidName> for Error {
+    fn from(item: InvalidName) -> Self {
+        Self::InvalidNameError(item)
+    }
+}
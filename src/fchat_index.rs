@@ -1,9 +1,12 @@
-use std::io::Seek;
+use std::io::{Seek, SeekFrom};
 use byteorder::ReadBytesExt;
 use std::io::Read;
-use crate::error::Error;
+use crate::error::{Error, read_record_start, truncated};
+use crate::fchat_message::{FChatMessage, STRUCTURAL_READ};
+use crate::names::validate_character_name;
+use crate::FChatMessageCursor;
 use byteorder::LittleEndian;
-use chrono::{NaiveTime, NaiveDate, NaiveDateTime};
+use chrono::{DateTime, FixedOffset, Local, NaiveTime, NaiveDate, NaiveDateTime, Offset, TimeDelta, TimeZone, Utc};
 use byteorder::WriteBytesExt;
 use std::{convert::TryInto, io::{Write}};
 pub type FChatIndexOffsetReaderResult = Result<FChatIndexOffset, Error>;
@@ -12,6 +15,63 @@ pub type FChatIndexReaderResult = Result<FChatIndex, Error>;
 pub type FChatIndexWriterResult = Result<(), Error>;
 
 const SECONDS_IN_DAY: u32 = 86400;
+/// Bytes an [offset](struct.FChatIndexOffset.html) takes up in an idx file: a u16 day and a 40 bit offset.
+pub const INDEX_OFFSET_LEN: u64 = 2 + 5;
+
+/// Which timezone's midnight a new day starts at when splitting a log into days for the index.
+/// The idx itself only stores dates, so the same boundary has to be used when reading it back.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DayBoundary {
+    /// Days start at midnight UTC.
+    #[default]
+    Utc,
+    /// Days start at midnight in the system's local timezone, like the F-Chat client does.
+    Local,
+    /// Days start at midnight at a fixed offset from UTC.
+    Offset(FixedOffset),
+}
+
+impl DayBoundary {
+    /// The day `datetime` falls on.
+    pub fn date_of(&self, datetime: &DateTime<Utc>) -> NaiveDate {
+        self.wall_clock(datetime).date()
+    }
+
+    /// What a clock in this timezone shows at `datetime`.
+    pub fn wall_clock(&self, datetime: &DateTime<Utc>) -> NaiveDateTime {
+        match self {
+            DayBoundary::Utc => datetime.naive_utc(),
+            DayBoundary::Local => datetime.with_timezone(&Local).naive_local(),
+            DayBoundary::Offset(offset) => datetime.with_timezone(offset).naive_local(),
+        }
+    }
+
+    /// The instant `date` starts at.
+    pub fn start_of(&self, date: NaiveDate) -> DateTime<Utc> {
+        let midnight = date.and_time(NaiveTime::MIN);
+        match self {
+            DayBoundary::Utc => midnight.and_utc(),
+            DayBoundary::Local => first_local_time_from(midnight),
+            DayBoundary::Offset(offset) => (midnight - *offset).and_utc(),
+        }
+    }
+}
+
+/// The first instant the local clock shows `time` or later. Midnight can fall into a DST gap, in which case the day
+/// starts when the gap ends. Gaps are usually an hour long, but a timezone moving across the date line can skip a day.
+fn first_local_time_from(time: NaiveDateTime) -> DateTime<Utc> {
+    let valid = |time: NaiveDateTime| Local.from_local_datetime(&time).earliest().map(|local| local.with_timezone(&Utc));
+    if let Some(start) = valid(time) {
+        return start;
+    }
+    // Find the first minute after the gap, then the first second of the gap's last minute that's valid.
+    (1..=2 * 24 * 60)
+        .map(|minutes| time + TimeDelta::minutes(minutes))
+        .find_map(|minute| valid(minute).map(|_| minute))
+        .and_then(|minute| (0..=59).rev().find_map(|seconds| valid(minute - TimeDelta::seconds(seconds))))
+        // No timezone has skipped more than a day, but if the clock never shows `time`, go by its offset at the time.
+        .unwrap_or_else(|| (time - Local.offset_from_utc_datetime(&time).fix()).and_utc())
+}
 
 pub struct FChatIndexOffset {
     pub date: NaiveDate,
@@ -24,37 +84,42 @@ pub struct FChatIndex {
 }
 
 impl FChatIndexOffset {
+    /// The instant this offset's day starts at under `day_boundary`.
+    pub fn start(&self, day_boundary: DayBoundary) -> DateTime<Utc> {
+        day_boundary.start_of(self.date)
+    }
+
     pub fn write_to_buf<B: Write + WriteBytesExt>(
         &self,
         buffer: &mut B,
     ) -> FChatIndexOffsetWriterResult {
-        let unix_timestamp = self.date.and_time(NaiveTime::from_hms(0, 0, 0)).timestamp();
+        let unix_timestamp = self.date.and_time(NaiveTime::MIN).and_utc().timestamp();
         let unix_days: u16 = (unix_timestamp / SECONDS_IN_DAY as i64).try_into()?;
         buffer.write_u16::<LittleEndian>(unix_days)?;
-        let mut offset = self.offset.clone();
+        let mut offset = self.offset;
         for _ in 0..5 {
             let byte_to_write: u8 = (offset & 0xff).try_into()?;
             buffer.write_u8(byte_to_write)?;
-            offset = offset >> 8;
+            offset >>= 8;
         }
         Ok(())
     }
 
     pub fn read_from_buf<T: Read + ReadBytesExt>(buf: &mut T) -> FChatIndexOffsetReaderResult {
-        let unix_days: u16;
-        match buf.read_u16::<LittleEndian>() {
-            Ok(number) => { unix_days = number; }
-            Err(err) => { return Err(Error::EOF(err)); }
-        };
+        let mut unix_days_raw = [0; 2];
+        read_record_start(buf, &mut unix_days_raw)?;
+        let unix_days: u16 = u16::from_le_bytes(unix_days_raw);
         let unix_timestamp = (unix_days as u64 * SECONDS_IN_DAY as u64) as i64;
-        let date = NaiveDateTime::from_timestamp(unix_timestamp, 0).date();
+        let date = DateTime::from_timestamp(unix_timestamp, 0)
+            .expect("u16 unix days are always in range")
+            .date_naive();
         let mut offset: u64 = 0;
         for n in 0..5 {
-            offset |= (buf.read_u8()? as u64) << (n * 8);
+            offset |= (buf.read_u8().map_err(truncated)? as u64) << (n * 8);
         }
         Ok(Self {
-            date: date,
-            offset: offset
+            date,
+            offset
         })
     }
 }
@@ -62,7 +127,7 @@ impl FChatIndexOffset {
 impl FChatIndex {
     pub fn new(name: String) -> Self {
         Self {
-            name: name,
+            name,
             offsets: Vec::new()
         }
     }
@@ -73,25 +138,81 @@ impl FChatIndex {
     ) -> FChatIndexWriterResult {
         let name_len: u8 = self.name.len().try_into()?;
         buffer.write_u8(name_len)?;
-        buffer.write(self.name.as_bytes())?;
+        buffer.write_all(self.name.as_bytes())?;
         Ok(())
     }
 
     pub fn read_header_from_buf<T: Read + ReadBytesExt>(buf: &mut T) -> FChatIndexReaderResult {
-        let name_length = buf.read_u8()?;
-        let mut name_raw: Vec<u8> = Vec::with_capacity(name_length as usize);
-        unsafe { name_raw.set_len(name_length as usize) }
-        buf.read_exact(&mut name_raw)?;
+        let name_length = buf.read_u8().map_err(truncated)?;
+        let mut name_raw: Vec<u8> = vec![0; name_length as usize];
+        buf.read_exact(&mut name_raw).map_err(truncated)?;
         let name = String::from_utf8(name_raw)?;
         let index = FChatIndex {
-            name: name,
+            name,
             offsets: Vec::new(),
         };
         Ok(index)
     }
 
+    /// Check the name could be an F-List character's, which it is for a private conversation's log. A channel's log
+    /// is named after the channel instead.
+    pub fn validate_name(&self) -> Result<(), Error> {
+        validate_character_name(&self.name)
+    }
+
+    /// The offset of the day block `datetime` falls in, which is the last day on or before it.
+    pub fn offset_for(&self, datetime: &DateTime<Utc>, day_boundary: DayBoundary) -> Option<&FChatIndexOffset> {
+        let date = day_boundary.date_of(datetime);
+        self.offsets.iter().take_while(|offset| offset.date <= date).last()
+    }
+
+    /// Check the index against its log: every offset has to point at the first message of its day, in order, and no day
+    /// in the log after the last offset may be missing. A log that can't be read where the index points also fails the check.
+    /// Days are split at midnight of `day_boundary`, which should be what the index was built with.
+    pub fn matches_log<T: Read + Seek>(&self, log: &mut T, day_boundary: DayBoundary) -> Result<bool, Error> {
+        let log_len = log.seek(SeekFrom::End(0))?;
+        let mut previous: Option<&FChatIndexOffset> = None;
+        for offset in &self.offsets {
+            if offset.offset >= log_len {
+                return Ok(false);
+            }
+            if let Some(previous) = previous {
+                if offset.offset <= previous.offset || offset.date <= previous.date {
+                    return Ok(false);
+                }
+            }
+            let mut cursor = FChatMessageCursor::at(&mut *log, offset.offset)?.with_options(STRUCTURAL_READ);
+            match cursor.next_message() {
+                Some(Ok((_, message))) if day_boundary.date_of(&message.utc_datetime()) == offset.date => {}
+                _ => { return Ok(false); }
+            }
+            cursor.seek_to(offset.offset)?;
+            match cursor.previous_message() {
+                None => {}
+                Some(Ok((_, message))) if day_boundary.date_of(&message.utc_datetime()) < offset.date => {}
+                _ => { return Ok(false); }
+            }
+            previous = Some(offset);
+        }
+        let last_date = self.offsets.last().map(|offset| offset.date);
+        log.seek(SeekFrom::Start(self.offsets.last().map_or(0, |offset| offset.offset)))?;
+        loop {
+            match FChatMessage::read_record_from_buf(log, STRUCTURAL_READ) {
+                Ok((message, _)) => {
+                    if last_date.is_none_or(|last_date| day_boundary.date_of(&message.utc_datetime()) > last_date) {
+                        return Ok(false);
+                    }
+                }
+                Err(Error::EOF(_)) => { break }
+                Err(_) => { return Ok(false); }
+            }
+        }
+        Ok(true)
+    }
+
     pub fn from_buf<T: Read + Seek + ReadBytesExt>(buf: &mut T) -> FChatIndexReaderResult {
-        let mut index = Self::read_header_from_buf(buf)?;
+        let mut index = Self::read_header_from_buf(buf).map_err(|err| err.at(0, None))?;
+        let header_len = 1 + index.name.len() as u64;
         loop {
             match FChatIndexOffset::read_from_buf(buf) {
                 Ok(index_offset) => {
@@ -101,7 +222,8 @@ impl FChatIndex {
                     break
                 }
                 Err(err) => {
-                    return Err(err);
+                    let entry = index.offsets.len() as u64;
+                    return Err(err.at(header_len + entry * INDEX_OFFSET_LEN, Some(entry)));
                 }
             }
         }
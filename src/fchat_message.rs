@@ -1,11 +1,12 @@
 use byteorder::ReadBytesExt;
 use byteorder::LittleEndian;
 use byteorder::WriteBytesExt;
-use crate::error::Error;
-use crate::error::{UnknownMessageType, BadMessageLength};
+use crate::error::{Error, read_record_start, truncated};
+use crate::error::{UnknownMessageType, BadMessageLength, InvalidSender, MessageTooLong};
 use crate::fchat_message::FChatMessageType::*;
-use chrono::{NaiveDateTime};
-use std::{io, fmt::{self, Debug, Display, Formatter}, convert::TryInto};
+use crate::names::validate_character_name;
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use std::{io, fmt::{self, Debug, Display, Formatter}, convert::{TryFrom, TryInto}};
 pub type FChatMessageReaderResult = Result<FChatMessage, Error>;
 pub type FChatMessageWriterResult = Result<(), Error>;
 
@@ -24,17 +25,112 @@ pub enum FChatMessageType {
     Warn(String),
     /// Event message (status changes)
     Event(String),
+    /// A type this library doesn't know, e.g. from a newer client. Keeps the type byte so it's written back unchanged.
+    Unknown(u8, String),
+}
+
+/// The type of a [message](enum.FChatMessageType.html) without its text. With the `serde` feature it serialises as its
+/// name, e.g. `"action"` or `"unknown(7)"`, so it can be used as a map key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(into = "String", try_from = "String"))]
+pub enum FChatMessageKind {
+    Message,
+    Action,
+    Ad,
+    Roll,
+    Warn,
+    Event,
+    Unknown(u8),
+}
+
+impl Display for FChatMessageKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            FChatMessageKind::Message => write!(f, "message"),
+            FChatMessageKind::Action => write!(f, "action"),
+            FChatMessageKind::Ad => write!(f, "ad"),
+            FChatMessageKind::Roll => write!(f, "roll"),
+            FChatMessageKind::Warn => write!(f, "warn"),
+            FChatMessageKind::Event => write!(f, "event"),
+            FChatMessageKind::Unknown(byte) => write!(f, "unknown({})", byte),
+        }
+    }
+}
+
+impl From<FChatMessageKind> for String {
+    fn from(kind: FChatMessageKind) -> Self {
+        kind.to_string()
+    }
+}
+
+impl TryFrom<String> for FChatMessageKind {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        let kind = match name.as_str() {
+            "message" => FChatMessageKind::Message,
+            "action" => FChatMessageKind::Action,
+            "ad" => FChatMessageKind::Ad,
+            "roll" => FChatMessageKind::Roll,
+            "warn" => FChatMessageKind::Warn,
+            "event" => FChatMessageKind::Event,
+            _ => name.strip_prefix("unknown(")
+                .and_then(|rest| rest.strip_suffix(')'))
+                .and_then(|byte| byte.parse().ok())
+                .map(FChatMessageKind::Unknown)
+                .ok_or_else(|| format!("{:?} is not a message type", name))?,
+        };
+        Ok(kind)
+    }
 }
 
 impl FChatMessageType {
-    fn bytes_used(&self) -> u64 {
+    /// The type of the message without its text.
+    pub fn kind(&self) -> FChatMessageKind {
+        match self {
+            Message(_) => FChatMessageKind::Message,
+            Action(_) => FChatMessageKind::Action,
+            Ad(_) => FChatMessageKind::Ad,
+            Roll(_) => FChatMessageKind::Roll,
+            Warn(_) => FChatMessageKind::Warn,
+            Event(_) => FChatMessageKind::Event,
+            Unknown(byte, _) => FChatMessageKind::Unknown(*byte),
+        }
+    }
+
+    /// The text of the message, whatever its type.
+    pub fn text(&self) -> &str {
         match self {
             Message(string) | Action(string) | Ad(string) | Roll(string) | Warn(string)
-            | Event(string) => string.as_bytes().len() as u64,
+            | Event(string) | Unknown(_, string) => string,
+        }
+    }
+
+    /// A message of the same type with different text.
+    pub fn with_text(&self, string: String) -> FChatMessageType {
+        match self {
+            Message(_) => Message(string),
+            Action(_) => Action(string),
+            Ad(_) => Ad(string),
+            Roll(_) => Roll(string),
+            Warn(_) => Warn(string),
+            Event(_) => Event(string),
+            Unknown(byte, _) => Unknown(*byte, string),
         }
     }
 
-    fn as_byte(&self) -> u8 {
+    fn bytes_used(&self) -> u64 {
+        self.text().len() as u64
+    }
+
+    /// Whether the type byte is one this library doesn't know.
+    pub fn is_unknown(&self) -> bool {
+        matches!(self, Unknown(_, _))
+    }
+
+    /// The type byte the message is stored with.
+    pub fn as_byte(&self) -> u8 {
         match self {
             Message(_) => 0,
             Action(_) => 1,
@@ -42,33 +138,26 @@ impl FChatMessageType {
             Roll(_) => 3,
             Warn(_) => 4,
             Event(_) => 5,
+            Unknown(byte, _) => *byte,
         }
     }
 
-    fn from_byte(byte: u8, string: String) -> Result<FChatMessageType, UnknownMessageType> {
+    fn from_byte(byte: u8, string: String) -> FChatMessageType {
         match byte {
-            0 => Ok(Message(string)),
-            1 => Ok(Action(string)),
-            2 => Ok(Ad(string)),
-            3 => Ok(Roll(string)),
-            4 => Ok(Warn(string)),
-            5 => Ok(Event(string)),
-            _ => Err(UnknownMessageType { found: byte }),
+            0 => Message(string),
+            1 => Action(string),
+            2 => Ad(string),
+            3 => Roll(string),
+            4 => Warn(string),
+            5 => Event(string),
+            _ => Unknown(byte, string),
         }
     }
 }
 
 impl Display for FChatMessageType {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        let string: &String = match self {
-            Message(this_string) => this_string,
-            Action(this_string) => this_string,
-            Ad(this_string) => this_string,
-            Roll(this_string) => this_string,
-            Warn(this_string) => this_string,
-            Event(this_string) => this_string,
-        };
-        write!(f, "{}", string)
+        write!(f, "{}", self.text())
     }
 }
 
@@ -89,6 +178,54 @@ pub struct FChatMessage {
     pub body: FChatMessageType,
 }
 
+/// What readers do with a record whose type byte isn't one this library knows.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FChatUnknownTypePolicy {
+    /// Return it as [Unknown](enum.FChatMessageType.html#variant.Unknown).
+    #[default]
+    Keep,
+    /// Leave it out and carry on with the next record.
+    Skip,
+    /// Fail with an [UnknownMessageTypeError](../error/enum.Error.html#variant.UnknownMessageTypeError).
+    Error,
+}
+
+/// How records get decoded when reading a log.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FChatReadOptions {
+    /// Replace invalid UTF-8 with U+FFFD instead of failing. Readers say whether the last message they read was
+    /// [decoded lossily](../struct.FChatMessageReader.html#method.lossy).
+    pub lossy_utf8: bool,
+    /// What to do with records of a type this library doesn't know. Readers that fail on one have still stepped past
+    /// it, so reading can carry on after the error.
+    pub unknown_types: FChatUnknownTypePolicy,
+}
+
+impl FChatReadOptions {
+    /// Fail for a message of an unknown type, if that's what these options do with them.
+    pub(crate) fn check_type(&self, message: &FChatMessage) -> Result<(), Error> {
+        match (&message.body, self.unknown_types) {
+            (Unknown(found, _), FChatUnknownTypePolicy::Error) => Err(UnknownMessageType { found: *found }.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// For reading a log only for its record boundaries and timestamps, where the text doesn't matter.
+pub(crate) const STRUCTURAL_READ: FChatReadOptions = FChatReadOptions {
+    lossy_utf8: true,
+    unknown_types: FChatUnknownTypePolicy::Keep,
+};
+
+/// Decode text read from a record, lossily if `lossy` is set. The flag returned says whether anything was replaced.
+fn decode_text(raw: Vec<u8>, lossy: bool) -> Result<(String, bool), Error> {
+    match String::from_utf8(raw) {
+        Ok(string) => Ok((string, false)),
+        Err(err) if lossy => Ok((String::from_utf8_lossy(err.as_bytes()).into_owned(), true)),
+        Err(err) => Err(err.into()),
+    }
+}
+
 /*
     How FChat messages are stored on disk:
         epoch seconds:  u32:LE
@@ -101,28 +238,114 @@ pub struct FChatMessage {
           \_ This is used when the file is being read in reverse. Also used to verify the message was read properly.
 */
 
+/// The longest sender a record can hold, in bytes.
+pub const MAX_SENDER_LEN: usize = u8::MAX as usize;
+
+/// The longest body a record from a sender `sender_len` bytes long can hold, in bytes.
+/// The reverse feed covers the whole record and is a u16, which makes this a bit under 65535.
+pub fn max_body_len(sender_len: usize) -> usize {
+    (u16::MAX as usize).saturating_sub(4 + 1 + 1 + sender_len + 2)
+}
+
+/// Where to cut `text` so the first part is at most `max` bytes. Cuts on a char boundary, outside of BBCode tags and
+/// after whitespace when there is some in the second half of the part.
+fn split_point(text: &str, max: usize) -> usize {
+    let mut end = max;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    if let Some(open) = text[..end].rfind('[') {
+        if open > 0 && !text[open..end].contains(']') {
+            end = open;
+        }
+    }
+    if let Some(space) = text[..end].rfind(char::is_whitespace) {
+        let after_space = space + text[space..].chars().next().map_or(1, char::len_utf8);
+        if after_space > end / 2 {
+            end = after_space;
+        }
+    }
+    if end == 0 {
+        end = text.chars().next().map_or(0, char::len_utf8);
+    }
+    end
+}
+
 impl FChatMessage {
+    /// When the message was sent. [datetime](#structfield.datetime) holds this as UTC without a timezone attached.
+    pub fn utc_datetime(&self) -> DateTime<Utc> {
+        self.datetime.and_utc()
+    }
+
+    /// When the message was sent, in the timezone `tz`.
+    pub fn datetime_in<Tz: TimeZone>(&self, tz: &Tz) -> DateTime<Tz> {
+        tz.from_utc_datetime(&self.datetime)
+    }
+
+    /// Split a message whose body is too long for one record into several consecutive messages with the same time,
+    /// sender and type. Messages that already fit come back as they are. A sender that's too long can't be split and is an error.
+    pub fn split_to_fit(self) -> Result<Vec<FChatMessage>, Error> {
+        self.check_sender()?;
+        let max = max_body_len(self.sender.len());
+        let mut rest: &str = self.body.text();
+        if rest.len() <= max {
+            return Ok(vec![self]);
+        }
+        let mut parts = Vec::new();
+        while !rest.is_empty() {
+            let end = if rest.len() <= max { rest.len() } else { split_point(rest, max) };
+            parts.push(FChatMessage {
+                datetime: self.datetime,
+                sender: self.sender.clone(),
+                body: self.body.with_text(rest[..end].to_string()),
+            });
+            rest = &rest[end..];
+        }
+        Ok(parts)
+    }
+
+    fn check_sender(&self) -> Result<(), InvalidSender> {
+        if self.sender.len() > MAX_SENDER_LEN {
+            return Err(InvalidSender {
+                sender: self.sender.clone(),
+                reason: format!("it is {} bytes long and can be at most {}", self.sender.len(), MAX_SENDER_LEN),
+            });
+        }
+        Ok(())
+    }
+
+    /// Check the sender could be an F-List character's name. Messages from the client itself, like the console's,
+    /// don't have a character as the sender.
+    pub fn validate_sender(&self) -> Result<(), Error> {
+        validate_character_name(&self.sender)
+    }
+
     pub fn bytes_used(&self) -> u64 {
-        return 4 + 1 + 1 + self.sender.as_bytes().len() as u64 + 2 + self.body.bytes_used();
+        4 + 1 + 1 + self.sender.len() as u64 + 2 + self.body.bytes_used()
     }
 
     pub fn write_to_buf<B: io::Write + WriteBytesExt>(
         &self,
         buffer: &mut B,
     ) -> FChatMessageWriterResult {
-        let epoch_seconds: u32 = self.datetime.timestamp().try_into()?;
-        let sender_length: u8 = self.sender.as_bytes().len().try_into()?;
+        self.check_sender()?;
+        let max = max_body_len(self.sender.len());
+        if self.body.bytes_used() > max as u64 {
+            return Err(Error::MessageTooLongError(MessageTooLong {
+                length: self.body.bytes_used() as usize,
+                max,
+            }));
+        }
+        let epoch_seconds: u32 = self.datetime.and_utc().timestamp().try_into()?;
+        let sender_length: u8 = self.sender.len().try_into()?;
         let message_length: u16 = self.body.bytes_used().try_into()?;
         let log_length: u16 = self.bytes_used().try_into()?;
         buffer.write_u32::<LittleEndian>(epoch_seconds)?;
         buffer.write_u8(self.body.as_byte())?;
         buffer.write_u8(sender_length)?;
-        buffer.write(self.sender.as_bytes())?;
+        buffer.write_all(self.sender.as_bytes())?;
         buffer.write_u16::<LittleEndian>(message_length)?;
-        buffer.write(match &self.body {
-            Message(string) | Action(string) | Ad(string) | Roll(string) | Warn(string)
-            | Event(string) => string.as_bytes(),
-        })?;
+        buffer.write_all(self.body.text().as_bytes())?;
         buffer.write_u16::<LittleEndian>(log_length)?;
         Ok(())
     }
@@ -130,38 +353,56 @@ impl FChatMessage {
     pub fn read_from_buf<B: io::Read + ReadBytesExt>(
         buffer: &mut B,
     ) -> FChatMessageReaderResult {
-        let datetime_buf: u32;
-        match buffer.read_u32::<LittleEndian>() {
-            Ok(number) => { datetime_buf = number; }
-            Err(err) => { return Err(Error::EOF(err)); }
-        };
-        let datetime: NaiveDateTime = NaiveDateTime::from_timestamp(datetime_buf as i64, 0);
-        let message_type: u8 = buffer.read_u8()?;
-        let sender_length: u8 = buffer.read_u8()?;
-        let mut sender_raw: Vec<u8> = Vec::with_capacity(sender_length as usize);
-        unsafe { sender_raw.set_len(sender_length as usize) }
-        buffer.read_exact(&mut sender_raw)?;
-        let sender = String::from_utf8(sender_raw)?;
-        let message_length: u16 = buffer.read_u16::<LittleEndian>()?;
-        let mut message_raw: Vec<u8> = Vec::with_capacity(message_length as usize);
-        unsafe { message_raw.set_len(message_length as usize) }
-        buffer.read_exact(&mut message_raw)?;
-        let message = String::from_utf8(message_raw)?;
+        Ok(Self::read_record_from_buf(buffer, FChatReadOptions::default())?.0)
+    }
+
+    /// Read a message using `options`, along with how many bytes its record took up in the log.
+    /// That is [bytes_used](#method.bytes_used) + 2 unless the text was decoded lossily.
+    pub fn read_record_from_buf<B: io::Read + ReadBytesExt>(
+        buffer: &mut B,
+        options: FChatReadOptions,
+    ) -> Result<(FChatMessage, u64), Error> {
+        let (message, record_len, _) = Self::read_decoded_record(buffer, options)?;
+        options.check_type(&message)?;
+        Ok((message, record_len))
+    }
+
+    /// [read_record_from_buf](#method.read_record_from_buf), also saying whether any text was decoded lossily. Records of
+    /// an unknown type are returned whatever the options do with them, for the caller to check once it's past them.
+    pub(crate) fn read_decoded_record<B: io::Read + ReadBytesExt>(
+        buffer: &mut B,
+        options: FChatReadOptions,
+    ) -> Result<(FChatMessage, u64, bool), Error> {
+        let mut datetime_raw = [0; 4];
+        read_record_start(buffer, &mut datetime_raw)?;
+        let datetime_buf: u32 = u32::from_le_bytes(datetime_raw);
+        let datetime: NaiveDateTime = DateTime::from_timestamp(datetime_buf as i64, 0)
+            .expect("u32 epoch seconds are always in range")
+            .naive_utc();
+        let message_type: u8 = buffer.read_u8().map_err(truncated)?;
+        let sender_length: u8 = buffer.read_u8().map_err(truncated)?;
+        let mut sender_raw: Vec<u8> = vec![0; sender_length as usize];
+        buffer.read_exact(&mut sender_raw).map_err(truncated)?;
+        let (sender, sender_lossy) = decode_text(sender_raw, options.lossy_utf8)?;
+        let message_length: u16 = buffer.read_u16::<LittleEndian>().map_err(truncated)?;
+        let mut message_raw: Vec<u8> = vec![0; message_length as usize];
+        buffer.read_exact(&mut message_raw).map_err(truncated)?;
+        let (message, message_lossy) = decode_text(message_raw, options.lossy_utf8)?;
         let fchat_message = FChatMessage {
-            datetime: datetime,
-            sender: sender,
-            body: FChatMessageType::from_byte(message_type, message)?,
+            datetime,
+            sender,
+            body: FChatMessageType::from_byte(message_type, message),
         };
-        let reverse_feed: u16 = buffer.read_u16::<LittleEndian>()?;
-        let actual_length = fchat_message.bytes_used();
-        if reverse_feed != actual_length.try_into()? {
+        let reverse_feed: u16 = buffer.read_u16::<LittleEndian>().map_err(truncated)?;
+        let actual_length = 4 + 1 + 1 + sender_length as u64 + 2 + message_length as u64;
+        if reverse_feed as u64 != actual_length {
             Err(Error::MessageLengthError(BadMessageLength {
                 message: fchat_message,
                 expected: reverse_feed as usize,
                 found: actual_length,
             }))
         } else {
-            Ok(fchat_message)
+            Ok((fchat_message, actual_length + 2, sender_lossy || message_lossy))
         }
     }
 }
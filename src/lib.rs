@@ -1,15 +1,34 @@
 pub mod fchat_message;
 pub mod error;
 pub mod fchat_index;
-use chrono::Datelike;
-use std::{fs::File};
+pub mod stats;
+pub mod sessions;
+pub mod manuscript;
+pub mod anonymise;
+pub mod container;
+pub mod profile;
+pub mod names;
+pub mod diff;
+pub mod dedup;
+#[cfg(feature = "encryption")]
+pub mod encrypted;
+#[cfg(feature = "compression")]
+pub mod compressed;
+#[cfg(feature = "archives")]
+pub mod archive;
+#[cfg(feature = "backup")]
+pub mod backup;
+use chrono::{Datelike, NaiveDate, NaiveDateTime};
+use std::{fs::{File, OpenOptions}, path::{Path, PathBuf}};
 use byteorder::{WriteBytesExt, ReadBytesExt, LittleEndian};
 use std::io::{Write, Seek};
-use std::io::{SeekFrom, Read};
-use crate::fchat_message::{FChatMessageReaderResult, FChatMessage};
+use std::io::{BufReader, SeekFrom, Read};
+use crate::fchat_message::{FChatMessageReaderResult, FChatMessage, FChatReadOptions, FChatUnknownTypePolicy, STRUCTURAL_READ};
 use crate::fchat_index::FChatIndex as Index;
 use crate::fchat_index::FChatIndexOffset as IndexOffset;
-use crate::error::Error;
+use crate::fchat_index::{DayBoundary, INDEX_OFFSET_LEN};
+use crate::error::{Error, ConformanceError, OutOfOrderMessage, truncated};
+use crate::names::same_character;
 
 // TODO: Look into dynamic dispatch
 // https://discordapp.com/channels/442252698964721669/443150878111694848/742291981849460736
@@ -24,13 +43,100 @@ pub fn different_day<A: Datelike, B: Datelike>(d1: A, d2: B) -> bool {
     d1.year() != d2.year() || d1.month() != d2.month() || d1.day() != d2.day()
 }
 
+/// Check the last record of a log using its reverse feed. A log whose last record was only partly written, e.g. because
+/// the client crashed while writing it, fails the check.
+pub fn log_tail_is_intact<T: Read + Seek>(log: &mut T) -> Result<bool, Error> {
+    let mut cursor = FChatMessageCursor::new(log)?.with_options(STRUCTURAL_READ);
+    cursor.seek_to_end()?;
+    match cursor.previous_message() {
+        Some(Err(err)) => match err.root() {
+            Error::IOError(_) => Err(err),
+            _ => Ok(false),
+        },
+        _ => Ok(true),
+    }
+}
+
+/// Scan a log forwards from the message boundary `scan_from` and get how much of it is whole records.
+/// Only a broken record at the very end counts as torn, anything broken before that is an error.
+pub(crate) fn intact_log_len<T: Read + Seek>(log: &mut T, scan_from: u64) -> Result<u64, Error> {
+    let log_len = log.seek(SeekFrom::End(0))?;
+    let mut position = scan_from.min(log_len);
+    log.seek(SeekFrom::Start(position))?;
+    loop {
+        match FChatMessage::read_record_from_buf(log, STRUCTURAL_READ) {
+            Ok((_, record_len)) => { position += record_len; }
+            Err(Error::EOF(_)) => { break }
+            Err(err) => {
+                let torn = match &err {
+                    Error::TruncatedError(_) => true,
+                    _ => log.stream_position()? >= log_len,
+                };
+                if torn {
+                    break;
+                }
+                return Err(err.at(position, None));
+            }
+        }
+    }
+    Ok(position)
+}
+
+/// The error for a log that has a torn record after its first `intact_len` bytes.
+fn torn_tail(intact_len: u64) -> Error {
+    let err = std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "the last record of the log is torn");
+    Error::TruncatedError(err).at(intact_len, None)
+}
+
 pub struct FChatMessageReader<'a> {
     buf: Box<dyn Read + 'a>,
+    options: FChatReadOptions,
+    position: u64,
+    record: u64,
+    path: Option<PathBuf>,
+    sender: Option<String>,
+    lossy: bool,
 }
 
-impl FChatMessageReader<'_> {
+impl<'a> FChatMessageReader<'a> {
     pub fn new<'message_reader, T: 'message_reader +  Read>(buf: T) -> FChatMessageReader<'message_reader> {
-        FChatMessageReader { buf: Box::new(buf) }
+        FChatMessageReader { buf: Box::new(buf), options: FChatReadOptions::default(), position: 0, record: 0, path: None, sender: None, lossy: false }
+    }
+
+    /// Decode messages using `options` instead of the defaults.
+    pub fn with_options(mut self, options: FChatReadOptions) -> FChatMessageReader<'a> {
+        self.options = options;
+        self
+    }
+
+    /// Read the log at `path`. Errors from reading it carry the path.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<FChatMessageReader<'static>, Error> {
+        let file = File::open(&path).map_err(|err| Error::from(err).in_file(&path))?;
+        Ok(FChatMessageReader::new(BufReader::new(file)).with_path(path))
+    }
+
+    /// Have errors from reading carry `path`, for readers over something other than a plain file.
+    pub fn with_path<P: AsRef<Path>>(mut self, path: P) -> FChatMessageReader<'a> {
+        self.path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Only return messages sent by `sender`, compared the way F-Chat compares [names](names/index.html).
+    pub fn with_sender(mut self, sender: &str) -> FChatMessageReader<'a> {
+        self.sender = Some(sender.to_string());
+        self
+    }
+
+    /// Byte offset of the next message, counted from where the reader started.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Whether the sender or body of the last message read wasn't valid UTF-8 and was
+    /// [decoded lossily](fchat_message/struct.FChatReadOptions.html#structfield.lossy_utf8), so its text holds
+    /// replacement characters where the bad bytes were.
+    pub fn lossy(&self) -> bool {
+        self.lossy
     }
 }
 
@@ -38,101 +144,416 @@ impl Iterator for FChatMessageReader<'_> {
     type Item = FChatMessageReaderResult;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match FChatMessage::read_from_buf(&mut self.buf) {
-            Ok(message) => {Some(Ok(message))}
-            Err(Error::EOF(_)) => { None }
-            Err(err) => { Some(Err(err)) }
+        loop {
+            let (start, record) = (self.position, self.record);
+            let result = FChatMessage::read_decoded_record(&mut self.buf, self.options).and_then(|(message, record_len, lossy)| {
+                self.position += record_len;
+                self.record += 1;
+                self.options.check_type(&message)?;
+                Ok((message, lossy))
+            });
+            match result {
+                Ok((message, lossy)) => {
+                    if skips(self.options, self.sender.as_deref(), &message) {
+                        continue;
+                    }
+                    self.lossy = lossy;
+                    return Some(Ok(message));
+                }
+                Err(Error::EOF(_)) => { return None }
+                Err(err) => {
+                    let err = err.at(start, Some(record));
+                    return Some(Err(match &self.path {
+                        Some(path) => err.in_file(path),
+                        None => err,
+                    }));
+                }
+            }
         }
     }
 }
 
-pub struct FChatMessageReaderReversed {
-    buf: Box<dyn ReadSeek>,
+/// Reads a log from the end back to the start.
+pub struct FChatMessageReaderReversed<'a> {
+    cursor: FChatMessageCursor<'a>,
+    failed: bool,
 }
 
-impl FChatMessageReaderReversed {
-    pub fn new<T: 'static + ReadSeek>(mut buf: T) -> FChatMessageReaderReversed {
-        buf.seek(SeekFrom::End(0)).unwrap();
-        FChatMessageReaderReversed { buf: Box::new(buf) }
+impl FChatMessageReaderReversed<'_> {
+    /// Read backwards from the end of the log.
+    pub fn new<'message_reader, T: 'message_reader + ReadSeek>(buf: T) -> Result<FChatMessageReaderReversed<'message_reader>, Error> {
+        let mut cursor = FChatMessageCursor::new(buf)?;
+        cursor.seek_to_end()?;
+        Ok(FChatMessageReaderReversed { cursor, failed: false })
+    }
+
+    /// Read backwards starting from the message that ends at `offset`.
+    pub fn from_offset<'message_reader, T: 'message_reader + ReadSeek>(buf: T, offset: u64) -> Result<FChatMessageReaderReversed<'message_reader>, Error> {
+        let cursor = FChatMessageCursor::at(buf, offset)?;
+        Ok(FChatMessageReaderReversed { cursor, failed: false })
     }
-}
 
-fn reverse_seek<B: Seek + ReadBytesExt>(buf: &mut B) -> std::io::Result<()> {
-    let reverse_feed = buf.read_u16::<LittleEndian>()?;
-    // I'm seeking -4 for some reason. Have to remember why.
-    buf.seek(SeekFrom::Current(-4 + (reverse_feed as i64) * -1))?;
-    Ok(())
+    /// Read backwards starting from the last message on or before `date`, using the index to find where that day ends.
+    pub fn from_end_of_day<'message_reader, T: 'message_reader + ReadSeek>(buf: T, index: &Index, date: NaiveDate) -> Result<FChatMessageReaderReversed<'message_reader>, Error> {
+        match index.offsets.iter().find(|offset| offset.date > date) {
+            Some(next_day) => Self::from_offset(buf, next_day.offset),
+            None => Self::new(buf),
+        }
+    }
+
+    /// Decode messages using `options` instead of the defaults.
+    pub fn with_options(mut self, options: FChatReadOptions) -> Self {
+        self.cursor = self.cursor.with_options(options);
+        self
+    }
+
+    /// Only return messages sent by `sender`, compared the way F-Chat compares [names](names/index.html).
+    pub fn with_sender(mut self, sender: &str) -> Self {
+        self.cursor = self.cursor.with_sender(sender);
+        self
+    }
+
+    /// Byte offset of the message boundary the reader will read backwards from next.
+    pub fn position(&self) -> u64 {
+        self.cursor.position()
+    }
+
+    /// Whether the last message read was [decoded lossily](struct.FChatMessageReader.html#method.lossy).
+    pub fn lossy(&self) -> bool {
+        self.cursor.lossy()
+    }
 }
 
-impl Iterator for FChatMessageReaderReversed {
+impl Iterator for FChatMessageReaderReversed<'_> {
     type Item = FChatMessageReaderResult;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.buf.seek(SeekFrom::Current(0)) {
-            Ok(pos) => {
-                if pos == 0 {
-                    return None;
-                }
+        if self.failed {
+            return None;
+        }
+        let position = self.cursor.position();
+        match self.cursor.previous_message()? {
+            Ok((_, message)) => { Some(Ok(message)) }
+            Err(err) => {
+                // The cursor can't step past a malformed message, so stop there instead of repeating the error.
+                self.failed = self.cursor.position() == position;
+                Some(Err(err))
             }
-            Err(err) => return Some(Err(Error::IOError(err)))
         }
+    }
+}
 
-        match reverse_seek(&mut self.buf) {
-            Ok(_) => {}
-            Err(err) => return Some(Err(Error::IOError(err)))
-        };
+/// Whether a reader leaves `message` out, for being of an unknown type it skips or not from the sender it's after.
+fn skips(options: FChatReadOptions, sender: Option<&str>, message: &FChatMessage) -> bool {
+    (options.unknown_types == FChatUnknownTypePolicy::Skip && message.body.is_unknown())
+        || sender.is_some_and(|sender| !same_character(sender, &message.sender))
+}
+
+/// A message read by [FChatMessageCursor](struct.FChatMessageCursor.html) paired with the byte offset it starts at.
+pub type FChatMessageCursorResult = Result<(u64, FChatMessage), Error>;
+
+/// A cursor over a log that sits on a message boundary and can be stepped forwards or backwards from there.
+pub struct FChatMessageCursor<'a> {
+    buf: Box<dyn ReadSeek + 'a>,
+    options: FChatReadOptions,
+    position: u64,
+    sender: Option<String>,
+    lossy: bool,
+}
+
+impl FChatMessageCursor<'_> {
+    /// Place the cursor at the start of the log.
+    pub fn new<'cursor, T: 'cursor + ReadSeek>(buf: T) -> Result<FChatMessageCursor<'cursor>, Error> {
+        Self::at(buf, 0)
+    }
 
-        let return_value = match FChatMessage::read_from_buf(&mut self.buf) {
-            Ok(message) => {Some(Ok(message))}
-            Err(Error::EOF(_)) => { None }
-            Err(err) => { Some(Err(err)) }
+    /// Place the cursor at `offset`, which has to be the start of a message or the end of the log.
+    pub fn at<'cursor, T: 'cursor + ReadSeek>(buf: T, offset: u64) -> Result<FChatMessageCursor<'cursor>, Error> {
+        let mut cursor = FChatMessageCursor {
+            buf: Box::new(buf),
+            options: FChatReadOptions::default(),
+            position: 0,
+            sender: None,
+            lossy: false,
         };
-        
-        match reverse_seek(&mut self.buf) {
-            Ok(_) => {}
-            Err(err) => return Some(Err(Error::IOError(err)))
+        cursor.seek_to(offset)?;
+        Ok(cursor)
+    }
+
+    /// Place the cursor at the first message of the day the index offset points to.
+    pub fn from_index_offset<'cursor, T: 'cursor + ReadSeek>(buf: T, offset: &IndexOffset) -> Result<FChatMessageCursor<'cursor>, Error> {
+        Self::at(buf, offset.offset)
+    }
+
+    /// Decode messages using `options` instead of the defaults.
+    pub fn with_options(mut self, options: FChatReadOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Step over messages not sent by `sender`, compared the way F-Chat compares [names](names/index.html).
+    pub fn with_sender(mut self, sender: &str) -> Self {
+        self.sender = Some(sender.to_string());
+        self
+    }
+
+    /// Byte offset of the message boundary the cursor is sitting on.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Whether the last message read was [decoded lossily](struct.FChatMessageReader.html#method.lossy).
+    pub fn lossy(&self) -> bool {
+        self.lossy
+    }
+
+    /// Move the cursor to `offset`. The offset isn't checked to be a message boundary until a message is read.
+    pub fn seek_to(&mut self, offset: u64) -> Result<(), Error> {
+        let len = self.buf.seek(SeekFrom::End(0))?;
+        if offset > len {
+            return Err(Error::ConformanceError(ConformanceError {
+                reason: format!("Offset {} is past the end of the log ({} bytes)", offset, len)
+            }));
+        }
+        self.position = offset;
+        Ok(())
+    }
+
+    /// Move the cursor past the last message of the log.
+    pub fn seek_to_end(&mut self) -> Result<(), Error> {
+        self.position = self.buf.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    /// Read the message after the cursor and step over it. Returns `None` at the end of the log.
+    pub fn next_message(&mut self) -> Option<FChatMessageCursorResult> {
+        loop {
+            match self.read_next() {
+                Ok(Some((_, message))) if self.skips(&message) => {}
+                result => return result.transpose(),
+            }
+        }
+    }
+
+    /// Step back over the message before the cursor and read it. Returns `None` at the start of the log.
+    pub fn previous_message(&mut self) -> Option<FChatMessageCursorResult> {
+        loop {
+            match self.read_previous() {
+                Ok(Some((_, message))) if self.skips(&message) => {}
+                result => return result.transpose(),
+            }
+        }
+    }
+
+    fn skips(&self, message: &FChatMessage) -> bool {
+        skips(self.options, self.sender.as_deref(), message)
+    }
+
+    fn read_next(&mut self) -> Result<Option<(u64, FChatMessage)>, Error> {
+        let start = self.position;
+        self.buf.seek(SeekFrom::Start(start))?;
+        let (message, record_len, lossy) = match FChatMessage::read_decoded_record(&mut self.buf, self.options) {
+            Ok(record) => { record }
+            Err(Error::EOF(_)) => { return Ok(None) }
+            Err(err) => { return Err(err.at(start, None)) }
         };
-        
-        return_value
+        self.position = start + record_len;
+        self.lossy = lossy;
+        self.options.check_type(&message).map_err(|err| err.at(start, None))?;
+        Ok(Some((start, message)))
+    }
+
+    fn read_previous(&mut self) -> Result<Option<(u64, FChatMessage)>, Error> {
+        let end = self.position;
+        if end == 0 {
+            return Ok(None);
+        }
+        let not_a_boundary = || Error::ConformanceError(ConformanceError {
+            reason: format!("Offset {} is not the end of a message", end)
+        });
+        if end < 2 {
+            return Err(not_a_boundary());
+        }
+        self.buf.seek(SeekFrom::Start(end - 2))?;
+        let reverse_feed = self.buf.read_u16::<LittleEndian>().map_err(truncated)? as u64;
+        let start = end.checked_sub(reverse_feed + 2).ok_or_else(not_a_boundary)?;
+        self.buf.seek(SeekFrom::Start(start))?;
+        let (message, record_len, lossy) = FChatMessage::read_decoded_record(&mut self.buf, self.options).map_err(|err| err.at(start, None))?;
+        if start + record_len != end {
+            return Err(not_a_boundary().at(start, None));
+        }
+        self.position = start;
+        self.lossy = lossy;
+        self.options.check_type(&message).map_err(|err| err.at(start, None))?;
+        Ok(Some((start, message)))
     }
 }
 
+/// What [FChatWriter](struct.FChatWriter.html) does with a message dated before the last one in the log.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FChatWriterOrdering {
+    /// Refuse the message with an [OutOfOrderError](error/enum.Error.html#variant.OutOfOrderError).
+    #[default]
+    Reject,
+    /// Write the message with its timestamp moved up to the last timestamp in the log.
+    Clamp,
+    /// Write the message as is. The idx can only point forwards through the log, so the message is filed under the day
+    /// already being written rather than its own date, and looking its date up in the idx won't find it.
+    Allow,
+}
+
+/// Settings for [FChatWriter](struct.FChatWriter.html) that also affect how an idx is built from an existing log.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FChatWriterOptions {
+    /// How messages that are older than the last one written get handled.
+    pub ordering: FChatWriterOrdering,
+    /// Which timezone's midnight starts a new day in the idx.
+    pub day_boundary: DayBoundary,
+    /// Write a message too long for one record as several consecutive records instead of failing.
+    pub split_long_messages: bool,
+    /// Have [open](struct.FChatWriter.html#method.open_with_options) cut a torn last record off the log instead of failing.
+    pub trim_torn_tail: bool,
+}
+
 pub struct FChatWriter<'writer> {
     pub index: Index,
     pub log_buf: Box<dyn ReadSeekWrite + 'writer>,
     pub idx_buf: Box<dyn ReadSeekWrite + 'writer>,
+    /// How messages get written and indexed.
+    pub options: FChatWriterOptions,
+    last_datetime: Option<NaiveDateTime>,
+    log_sync: Option<File>,
+    idx_sync: Option<File>,
+}
+
+/// What [FChatWriter::recover](struct.FChatWriter.html#method.recover) had to change to make a log and idx agree again.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FChatRecovery {
+    /// Bytes of a torn record cut from the end of the log.
+    pub log_bytes_removed: u64,
+    /// Bytes cut from the end of the idx, either a torn entry or entries pointing past the end of the log.
+    pub idx_bytes_removed: u64,
+    /// Entries added to the idx for days that made it into the log but not the idx.
+    pub idx_entries_added: usize,
 }
 
 impl FChatWriter<'_> {
 
+    /// Open the log and idx at the given paths, creating whichever is missing, and get a writer positioned at the end of the log.
+    /// An idx that can't be read or doesn't [match](fchat_index/struct.FChatIndex.html#method.matches_log) the log is regenerated.
+    /// `name` is only used when the idx has to be created from scratch. Writes made with [write_batch](#method.write_batch) are synced.
+    /// A log with a torn last record is refused unless [trim_torn_tail](struct.FChatWriterOptions.html#structfield.trim_torn_tail) is set.
+    pub fn open<P: AsRef<Path>, Q: AsRef<Path>>(log_path: P, idx_path: Q, name: String) -> Result<FChatWriter<'static>, Error> {
+        Self::open_with_options(log_path, idx_path, name, FChatWriterOptions::default())
+    }
+
+    /// [open](#method.open) with options other than the defaults.
+    pub fn open_with_options<P: AsRef<Path>, Q: AsRef<Path>>(log_path: P, idx_path: Q, name: String, options: FChatWriterOptions) -> Result<FChatWriter<'static>, Error> {
+        let (log_path, idx_path) = (log_path.as_ref(), idx_path.as_ref());
+        let in_log = |err: Error| err.in_file(log_path);
+        let in_idx = |err: Error| err.in_file(idx_path);
+        let mut open_options = OpenOptions::new();
+        open_options.read(true).write(true).create(true);
+        let mut log_file = open_options.open(log_path).map_err(|err| in_log(err.into()))?;
+        let mut idx_file = open_options.open(idx_path).map_err(|err| in_idx(err.into()))?;
+        if !log_tail_is_intact(&mut log_file).map_err(in_log)? {
+            let scan_from = Index::from_buf(&mut idx_file).ok()
+                .and_then(|index| index.offsets.last().map(|offset| offset.offset))
+                .unwrap_or(0);
+            idx_file.seek(SeekFrom::Start(0))?;
+            // The idx could point somewhere that isn't a record, so fall back to scanning the whole log.
+            let intact_len = match intact_log_len(&mut log_file, scan_from) {
+                Ok(intact_len) => intact_len,
+                Err(_) => intact_log_len(&mut log_file, 0).map_err(in_log)?,
+            };
+            if !options.trim_torn_tail {
+                return Err(in_log(torn_tail(intact_len)));
+            }
+            log_file.set_len(intact_len).map_err(|err| in_log(err.into()))?;
+        }
+        log_file.seek(SeekFrom::Start(0))?;
+        let header_is_readable = Index::read_header_from_buf(&mut idx_file).is_ok();
+        idx_file.seek(SeekFrom::Start(0))?;
+        if !header_is_readable {
+            idx_file.set_len(0).map_err(|err| in_idx(err.into()))?;
+            let writer = FChatWriter::from_log_with_options(log_file.try_clone()?, idx_file.try_clone()?, name, options).map_err(in_log)?;
+            return writer.sync_to(&log_file, &idx_file);
+        }
+        let idx_matches = match Index::from_buf(&mut idx_file) {
+            Ok(index) => index.matches_log(&mut log_file, options.day_boundary).map_err(in_log)?,
+            Err(_) => false,
+        };
+        log_file.seek(SeekFrom::Start(0))?;
+        idx_file.seek(SeekFrom::Start(0))?;
+        if !idx_matches {
+            Self::regenerate_idx_with_options(&log_file, &idx_file, options).map_err(in_log)?;
+        }
+        let mut writer = FChatWriter::from_idx(log_file.try_clone()?, idx_file.try_clone()?).map_err(in_log)?;
+        writer.options = options;
+        writer.sync_to(&log_file, &idx_file)
+    }
+
     /// Using an existing idx file and log file, initialize the index with the idx file
+    /// A log with a [torn](fn.log_tail_is_intact.html) last record is refused with a [TruncatedError](error/enum.Error.html#variant.TruncatedError)
+    /// located at the end of the last whole record, instead of being appended to.
     pub fn from_idx<'writer, A: 'writer + ReadSeekWrite, B: 'writer + ReadSeekWrite>(mut log_buf: A, mut idx_buf: B) -> Result<FChatWriter<'writer>, Error> {
         let index = Index::from_buf(&mut idx_buf)?;
+        if !log_tail_is_intact(&mut log_buf)? {
+            let intact_len = intact_log_len(&mut log_buf, index.offsets.last().map_or(0, |offset| offset.offset))?;
+            return Err(torn_tail(intact_len));
+        }
+        let last_datetime = {
+            let mut cursor = FChatMessageCursor::new(&mut log_buf)?.with_options(STRUCTURAL_READ);
+            cursor.seek_to_end()?;
+            match cursor.previous_message() {
+                Some(result) => Some(result?.1.datetime),
+                None => None,
+            }
+        };
         log_buf.seek(SeekFrom::End(0))?;
         Ok(FChatWriter {
-            index: index,
+            index,
             log_buf: Box::new(log_buf),
             idx_buf: Box::new(idx_buf),
+            options: FChatWriterOptions::default(),
+            last_datetime,
+            log_sync: None,
+            idx_sync: None,
         })
     }
 
     /// Using an existing log file and missing idx, initialize the index with the log and write to the idx file
     pub fn from_log<'writer, A: 'writer + ReadSeekWrite, B: 'writer + ReadSeekWrite>(log_buf: A, idx_buf: B, name: String) -> Result<FChatWriter<'writer>, Error> {
+        Self::from_log_with_options(log_buf, idx_buf, name, FChatWriterOptions::default())
+    }
+
+    /// [from_log](#method.from_log) with options other than the defaults.
+    pub fn from_log_with_options<'writer, A: 'writer + ReadSeekWrite, B: 'writer + ReadSeekWrite>(log_buf: A, idx_buf: B, name: String, options: FChatWriterOptions) -> Result<FChatWriter<'writer>, Error> {
         let mut writer = Self::new(log_buf, idx_buf, name)?;
+        writer.options = options;
         writer.write_offsets_from_log()?;
         Ok(writer)
     }
 
     /// Using an existing log file and broken idx, repair the idx file.
-    pub fn regenerate_idx(mut log_file: &File, mut idx_file: &File) -> Result<(), Error> {
+    pub fn regenerate_idx(log_file: &File, idx_file: &File) -> Result<(), Error> {
+        Self::regenerate_idx_with_options(log_file, idx_file, FChatWriterOptions::default())
+    }
+
+    /// [regenerate_idx](#method.regenerate_idx) with options other than the defaults.
+    pub fn regenerate_idx_with_options(mut log_file: &File, mut idx_file: &File, options: FChatWriterOptions) -> Result<(), Error> {
         //idx_buf.set_len();
         let index = Index::read_header_from_buf(&mut idx_file)?;
-        let new_size = idx_file.seek(SeekFrom::Current(0))?;
+        let new_size = idx_file.stream_position()?;
         idx_file.set_len(new_size)?;
         let mut writer = FChatWriter {
-            index: index,
+            index,
             log_buf: Box::new(log_file),
             idx_buf: Box::new(idx_file),
+            options,
+            last_datetime: None,
+            log_sync: None,
+            idx_sync: None,
         };
         writer.write_offsets_from_log()?;
         log_file.seek(SeekFrom::Start(0))?;
@@ -143,11 +564,15 @@ impl FChatWriter<'_> {
     pub fn new<'writer, A: 'writer + ReadSeekWrite, B: 'writer + ReadSeekWrite>(log_buf: A, idx_buf: B, name: String) -> Result<FChatWriter<'writer>, Error> {
         let mut writer = FChatWriter {
             index: Index {
-                name: name,
+                name,
                 offsets: Vec::new()
             },
             log_buf: Box::new(log_buf),
             idx_buf: Box::new(idx_buf),
+            options: FChatWriterOptions::default(),
+            last_datetime: None,
+            log_sync: None,
+            idx_sync: None,
         };
         writer.index.write_header_to_buf(&mut writer.idx_buf)?;
         Ok(writer)
@@ -155,37 +580,189 @@ impl FChatWriter<'_> {
 
     fn write_offsets_from_log(&mut self) -> Result<(), Error> {
         loop {
-            match FChatMessage::read_from_buf(&mut self.log_buf) {
-                Ok(message) => {
-                    self.update_idx_with_message(message)?;
+            let position = self.log_buf.stream_position()?;
+            match FChatMessage::read_record_from_buf(&mut self.log_buf, STRUCTURAL_READ) {
+                Ok((message, record_len)) => {
+                    self.update_idx_with_message(message, record_len)?;
                 }
                 Err(Error::EOF(_)) => { break }
-                Err(err) => { return Err(err); }
+                Err(err) => { return Err(err.at(position, None)); }
             }
         }
         Ok(())
     }
 
+    /// Timestamp of the newest message in the log, if there is one.
+    pub fn last_datetime(&self) -> Option<NaiveDateTime> {
+        self.last_datetime
+    }
+
+    /// Have [write_batch](#method.write_batch) sync these files to disk, the log before the idx.
+    /// These should be the files the writer's buffers write to.
+    pub fn sync_to(mut self, log_file: &File, idx_file: &File) -> Result<Self, Error> {
+        self.log_sync = Some(log_file.try_clone()?);
+        self.idx_sync = Some(idx_file.try_clone()?);
+        Ok(self)
+    }
+
     /// Commit message to file and update the idx if needed.
+    /// A message older than the last one is handled according to the [ordering](struct.FChatWriterOptions.html#structfield.ordering) option.
     pub fn write_message(&mut self, message: FChatMessage) -> Result<(), Error> {
         //self.log_buf.seek(SeekFrom::End(0))?;
-        message.write_to_buf(&mut self.log_buf)?;
-        self.update_idx_with_message(message)?;
+        let message = self.order_message(self.last_datetime, message)?;
+        for part in self.split_message(message)? {
+            part.write_to_buf(&mut self.log_buf)?;
+            let record_len = part.bytes_used() + 2;
+            self.update_idx_with_message(part, record_len)?;
+        }
         Ok(())
     }
 
+    /// Commit several messages at once. Nothing is written unless every message can be.
+    /// The log is written and synced before any idx entries are, so a crash can only leave the idx behind the log, which
+    /// [recover](#method.recover) repairs.
+    pub fn write_batch<I: IntoIterator<Item = FChatMessage>>(&mut self, messages: I) -> Result<(), Error> {
+        let log_start = self.log_buf.stream_position()?;
+        let mut last_datetime = self.last_datetime;
+        let mut last_date = self.index.offsets.last().map(|offset| offset.date);
+        let mut records: Vec<u8> = Vec::new();
+        let mut new_offsets: Vec<IndexOffset> = Vec::new();
+        for message in messages {
+            let message = self.order_message(last_datetime, message)?;
+            for part in self.split_message(message)? {
+                let offset = log_start + records.len() as u64;
+                part.write_to_buf(&mut records)?;
+                let date = self.options.day_boundary.date_of(&part.utc_datetime());
+                if last_date.is_none_or(|last_date| date > last_date) {
+                    new_offsets.push(IndexOffset { date, offset });
+                    last_date = Some(date);
+                }
+                if last_datetime.is_none_or(|last_datetime| part.datetime > last_datetime) {
+                    last_datetime = Some(part.datetime);
+                }
+            }
+        }
+        self.log_buf.write_all(&records)?;
+        self.log_buf.flush()?;
+        if let Some(log_file) = &self.log_sync {
+            log_file.sync_data()?;
+        }
+        for offset in &new_offsets {
+            offset.write_to_buf(&mut self.idx_buf)?;
+        }
+        self.idx_buf.flush()?;
+        if let Some(idx_file) = &self.idx_sync {
+            idx_file.sync_data()?;
+        }
+        self.index.offsets.extend(new_offsets);
+        self.last_datetime = last_datetime;
+        Ok(())
+    }
+
+    /// Repair a log and idx left inconsistent by a crash, before opening them with [from_idx](#method.from_idx).
+    /// A torn record at the end of the log and a torn entry at the end of the idx are cut off, idx entries pointing past
+    /// the log are dropped and days missing from the idx are added.
+    pub fn recover(log_file: &File, idx_file: &File) -> Result<FChatRecovery, Error> {
+        Self::recover_with_options(log_file, idx_file, FChatWriterOptions::default())
+    }
+
+    /// [recover](#method.recover) with options other than the defaults.
+    pub fn recover_with_options(mut log_file: &File, mut idx_file: &File, options: FChatWriterOptions) -> Result<FChatRecovery, Error> {
+        let mut recovery = FChatRecovery::default();
+        let index_len = idx_file.seek(SeekFrom::End(0))?;
+        idx_file.seek(SeekFrom::Start(0))?;
+        Index::read_header_from_buf(&mut idx_file)?;
+        let header_len = idx_file.stream_position()?;
+        let whole_entries_len = header_len + (index_len - header_len) / INDEX_OFFSET_LEN * INDEX_OFFSET_LEN;
+        let mut whole_entries = vec![0; whole_entries_len as usize];
+        idx_file.seek(SeekFrom::Start(0))?;
+        idx_file.read_exact(&mut whole_entries)?;
+        let mut index = Index::from_buf(&mut std::io::Cursor::new(whole_entries))?;
+
+        // Entries pointing past the end of the log can't be scanned from, so they go before looking for a torn tail.
+        let log_len = log_file.seek(SeekFrom::End(0))?;
+        index.offsets.retain(|offset| offset.offset < log_len);
+        let scan_from = index.offsets.last().map_or(0, |offset| offset.offset);
+        let position = match intact_log_len(&mut log_file, scan_from) {
+            Ok(position) => position,
+            Err(_) => intact_log_len(&mut log_file, 0)?,
+        };
+        if position < log_len {
+            log_file.set_len(position)?;
+            recovery.log_bytes_removed = log_len - position;
+        }
+
+        index.offsets.retain(|offset| offset.offset < position);
+        let kept_len = header_len + index.offsets.len() as u64 * INDEX_OFFSET_LEN;
+        if kept_len < index_len {
+            idx_file.set_len(kept_len)?;
+            recovery.idx_bytes_removed = index_len - kept_len;
+        }
+
+        let kept_entries = index.offsets.len();
+        log_file.seek(SeekFrom::Start(index.offsets.last().map_or(0, |offset| offset.offset)))?;
+        idx_file.seek(SeekFrom::End(0))?;
+        let mut writer = FChatWriter {
+            index,
+            log_buf: Box::new(log_file),
+            idx_buf: Box::new(idx_file),
+            options,
+            last_datetime: None,
+            log_sync: None,
+            idx_sync: None,
+        };
+        writer.write_offsets_from_log()?;
+        recovery.idx_entries_added = writer.index.offsets.len() - kept_entries;
+        log_file.seek(SeekFrom::Start(0))?;
+        idx_file.seek(SeekFrom::Start(0))?;
+        Ok(recovery)
+    }
+
+    /// Split a message into as many records as it needs if [split_long_messages](struct.FChatWriterOptions.html#structfield.split_long_messages) is set.
+    fn split_message(&self, message: FChatMessage) -> Result<Vec<FChatMessage>, Error> {
+        if self.options.split_long_messages {
+            message.split_to_fit()
+        } else {
+            Ok(vec![message])
+        }
+    }
+
+    /// Apply the [ordering](struct.FChatWriterOptions.html#structfield.ordering) policy to a message that is about to be written after `last_datetime`.
+    fn order_message(&self, last_datetime: Option<NaiveDateTime>, mut message: FChatMessage) -> Result<FChatMessage, Error> {
+        if let Some(last_datetime) = last_datetime {
+            if message.datetime < last_datetime {
+                match self.options.ordering {
+                    FChatWriterOrdering::Reject => {
+                        return Err(Error::OutOfOrderError(OutOfOrderMessage {
+                            message,
+                            last_datetime,
+                        }));
+                    }
+                    FChatWriterOrdering::Clamp => { message.datetime = last_datetime; }
+                    FChatWriterOrdering::Allow => {}
+                }
+            }
+        }
+        Ok(message)
+    }
+
     /// This is typically reading with the reader or writing with the writer, so the seek location of the log_buf should be right after the read message.
     /// Aka, this function is run after reading a message or writing it from/to the log stream.
-    fn update_idx_with_message(&mut self, message: FChatMessage) -> Result<(), Error> {
+    /// Only a day later than the last indexed one gets an entry, so a message that's out of order can't put the idx out of order too.
+    fn update_idx_with_message(&mut self, message: FChatMessage, record_len: u64) -> Result<(), Error> {
+        if self.last_datetime.is_none_or(|last_datetime| message.datetime > last_datetime) {
+            self.last_datetime = Some(message.datetime);
+        }
+        let date = self.options.day_boundary.date_of(&message.utc_datetime());
         if match self.index.offsets.last() {
             Some(offset) => {
-                different_day(message.datetime, offset.date)
+                date > offset.date
             }
             None => { true }
         } {
-            let offset_pos = self.log_buf.seek(SeekFrom::Current(0))? - (message.bytes_used() + 2);
+            let offset_pos = self.log_buf.stream_position()? - record_len;
             let offset = IndexOffset {
-                date: message.datetime.date(),
+                date,
                 offset: offset_pos
             };
             offset.write_to_buf(&mut self.idx_buf)?;
@@ -0,0 +1,270 @@
+//! Turning a scene into something readable: dialogue and actions only, with the BBCode converted to Markdown or HTML.
+use std::io::Write;
+use chrono::{DateTime, Utc};
+use crate::error::Error;
+use crate::fchat_message::{FChatMessage, FChatMessageType};
+use crate::names::same_character;
+
+/// What a [manuscript](fn.write_manuscript.html) is written as.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ManuscriptFormat {
+    #[default]
+    Markdown,
+    /// An HTML fragment, one `<p>` per message.
+    Html,
+}
+
+/// Which messages go into a manuscript, and how it's written.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ManuscriptOptions {
+    pub format: ManuscriptFormat,
+    /// Leave out messages sent before this.
+    pub from: Option<DateTime<Utc>>,
+    /// Leave out messages sent at or after this.
+    pub until: Option<DateTime<Utc>>,
+    /// Only keep messages from these senders, whatever the case of their names. Empty keeps everyone.
+    pub participants: Vec<String>,
+}
+
+impl ManuscriptOptions {
+    /// Whether `message` belongs in the manuscript. Only messages and actions do; ads, rolls, warnings and events never do.
+    pub fn includes(&self, message: &FChatMessage) -> bool {
+        let datetime = message.utc_datetime();
+        matches!(message.body, FChatMessageType::Message(_) | FChatMessageType::Action(_))
+            && self.from.is_none_or(|from| datetime >= from)
+            && self.until.is_none_or(|until| datetime < until)
+            && (self.participants.is_empty() || self.participants.iter().any(|participant| same_character(participant, &message.sender)))
+    }
+}
+
+/// Write the messages `options` includes to `out`, actions as prose and messages as dialogue. Returns how many were written.
+pub fn write_manuscript<W, I>(messages: I, options: &ManuscriptOptions, out: &mut W) -> Result<u64, Error>
+where
+    W: Write,
+    I: IntoIterator<Item = Result<FChatMessage, Error>>,
+{
+    let mut written = 0;
+    for message in messages {
+        let message = message?;
+        if !options.includes(&message) {
+            continue;
+        }
+        let paragraph = match options.format {
+            ManuscriptFormat::Markdown => markdown_paragraph(&message),
+            ManuscriptFormat::Html => html_paragraph(&message),
+        };
+        out.write_all(paragraph.as_bytes())?;
+        written += 1;
+    }
+    Ok(written)
+}
+
+fn markdown_paragraph(message: &FChatMessage) -> String {
+    let sender = escape_markdown(&message.sender);
+    let text = bbcode_to_markdown(message.body.text()).replace('\n', "  \n");
+    match message.body {
+        FChatMessageType::Action(_) => format!("*{}* {}\n\n", sender, text),
+        _ => format!("**{}:** {}\n\n", sender, text),
+    }
+}
+
+fn html_paragraph(message: &FChatMessage) -> String {
+    let sender = escape_html(&message.sender);
+    let text = bbcode_to_html(message.body.text()).replace('\n', "<br>\n");
+    match message.body {
+        FChatMessageType::Action(_) => format!("<p class=\"action\"><em>{}</em> {}</p>\n", sender, text),
+        _ => format!("<p class=\"message\"><strong>{}:</strong> {}</p>\n", sender, text),
+    }
+}
+
+/// Convert F-Chat BBCode to Markdown. Tags Markdown has no equivalent for keep their text and lose their formatting,
+/// and eicons are dropped.
+pub fn bbcode_to_markdown(bbcode: &str) -> String {
+    let mut markdown = String::new();
+    render(&parse_bbcode(bbcode), ManuscriptFormat::Markdown, &mut markdown);
+    markdown
+}
+
+/// Convert F-Chat BBCode to HTML. Text is escaped, links are only kept for http and https URLs, and eicons are dropped.
+pub fn bbcode_to_html(bbcode: &str) -> String {
+    let mut html = String::new();
+    render(&parse_bbcode(bbcode), ManuscriptFormat::Html, &mut html);
+    html
+}
+
+const TAGS: &[&str] = &[
+    "b", "i", "u", "s", "sup", "sub", "url", "user", "icon", "eicon", "color", "spoiler", "noparse", "big", "small",
+    "collapse", "quote", "center", "left", "right", "justify", "indent", "heading",
+];
+
+enum Node {
+    Text(String),
+    Tag { name: String, argument: Option<String>, children: Vec<Node> },
+}
+
+struct OpenTag {
+    name: String,
+    argument: Option<String>,
+    children: Vec<Node>,
+}
+
+enum Token<'a> {
+    Open(String, Option<&'a str>),
+    Close(String),
+}
+
+/// Parse a tag at the start of `input`, returning it and its length. Unknown tags aren't tags, they're text.
+fn parse_tag(input: &str) -> Option<(Token<'_>, usize)> {
+    let end = input.find(']')?;
+    let inner = &input[1..end];
+    let (token, name) = match inner.strip_prefix('/') {
+        Some(name) => (None, name),
+        None => match inner.split_once('=') {
+            Some((name, argument)) => (Some(argument), name),
+            None => (None, inner),
+        },
+    };
+    let name = name.to_ascii_lowercase();
+    if !TAGS.contains(&name.as_str()) {
+        return None;
+    }
+    let token = if inner.starts_with('/') { Token::Close(name) } else { Token::Open(name, token) };
+    Some((token, end + 1))
+}
+
+fn push_text(children: &mut Vec<Node>, text: &str) {
+    if let Some(Node::Text(last)) = children.last_mut() {
+        last.push_str(text);
+    } else {
+        children.push(Node::Text(text.to_string()));
+    }
+}
+
+/// Close the innermost open tag into its parent.
+fn close(stack: &mut Vec<OpenTag>, root: &mut Vec<Node>) {
+    if let Some(tag) = stack.pop() {
+        let node = Node::Tag { name: tag.name, argument: tag.argument, children: tag.children };
+        stack.last_mut().map_or(&mut *root, |parent| &mut parent.children).push(node);
+    }
+}
+
+/// Parse BBCode the way the F-Chat client does: closing a tag closes any still open inside it, closing a tag that
+/// isn't open is text, and tags left open at the end are closed there.
+fn parse_bbcode(input: &str) -> Vec<Node> {
+    let mut root = Vec::new();
+    let mut stack: Vec<OpenTag> = Vec::new();
+    let mut rest = input;
+    while !rest.is_empty() {
+        let text_len = rest.find('[').unwrap_or(rest.len());
+        if text_len > 0 {
+            push_text(stack.last_mut().map_or(&mut root, |tag| &mut tag.children), &rest[..text_len]);
+            rest = &rest[text_len..];
+            continue;
+        }
+        match parse_tag(rest) {
+            Some((Token::Open(name, _), len)) if name == "noparse" => {
+                rest = &rest[len..];
+                let end = rest.to_ascii_lowercase().find("[/noparse]").unwrap_or(rest.len());
+                push_text(stack.last_mut().map_or(&mut root, |tag| &mut tag.children), &rest[..end]);
+                rest = &rest[(end + "[/noparse]".len()).min(rest.len())..];
+            }
+            Some((Token::Open(name, argument), len)) => {
+                stack.push(OpenTag { name, argument: argument.map(str::to_string), children: Vec::new() });
+                rest = &rest[len..];
+            }
+            Some((Token::Close(name), len)) if stack.iter().any(|tag| tag.name == name) => {
+                while stack.last().is_some_and(|tag| tag.name != name) {
+                    close(&mut stack, &mut root);
+                }
+                close(&mut stack, &mut root);
+                rest = &rest[len..];
+            }
+            _ => {
+                push_text(stack.last_mut().map_or(&mut root, |tag| &mut tag.children), "[");
+                rest = &rest[1..];
+            }
+        }
+    }
+    while !stack.is_empty() {
+        close(&mut stack, &mut root);
+    }
+    root
+}
+
+fn plain_text(nodes: &[Node]) -> String {
+    let mut text = String::new();
+    for node in nodes {
+        match node {
+            Node::Text(string) => text.push_str(string),
+            Node::Tag { children, .. } => text.push_str(&plain_text(children)),
+        }
+    }
+    text
+}
+
+fn is_web_url(url: &str) -> bool {
+    let lowercase = url.to_ascii_lowercase();
+    lowercase.starts_with("http://") || lowercase.starts_with("https://")
+}
+
+fn render(nodes: &[Node], format: ManuscriptFormat, out: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => match format {
+                ManuscriptFormat::Markdown => out.push_str(&escape_markdown(text)),
+                ManuscriptFormat::Html => out.push_str(&escape_html(text)),
+            },
+            Node::Tag { name, argument, children } => render_tag(name, argument.as_deref(), children, format, out),
+        }
+    }
+}
+
+fn render_tag(name: &str, argument: Option<&str>, children: &[Node], format: ManuscriptFormat, out: &mut String) {
+    let wrap = |out: &mut String, open: &str, close: &str| {
+        out.push_str(open);
+        render(children, format, out);
+        out.push_str(close);
+    };
+    match (format, name) {
+        (_, "eicon") => {}
+        (_, "url") => {
+            let url = argument.map_or_else(|| plain_text(children), str::to_string);
+            if !is_web_url(&url) {
+                render(children, format, out);
+            } else if format == ManuscriptFormat::Markdown {
+                let url = url.replace(' ', "%20").replace('(', "%28").replace(')', "%29");
+                wrap(out, "[", &format!("]({})", url));
+            } else {
+                wrap(out, &format!("<a href=\"{}\">", escape_html(&url)), "</a>");
+            }
+        }
+        (ManuscriptFormat::Markdown, "b") => wrap(out, "**", "**"),
+        (ManuscriptFormat::Markdown, "i") => wrap(out, "*", "*"),
+        (ManuscriptFormat::Markdown, "s") => wrap(out, "~~", "~~"),
+        (ManuscriptFormat::Html, "b") => wrap(out, "<strong>", "</strong>"),
+        (ManuscriptFormat::Html, "i") => wrap(out, "<em>", "</em>"),
+        (ManuscriptFormat::Html, tag @ ("u" | "s" | "sup" | "sub")) => {
+            wrap(out, &format!("<{}>", tag), &format!("</{}>", tag))
+        }
+        (ManuscriptFormat::Html, "color") if argument.is_some_and(|color| color.chars().all(|c| c.is_ascii_alphabetic())) => {
+            wrap(out, &format!("<span style=\"color: {}\">", argument.unwrap_or_default()), "</span>")
+        }
+        (ManuscriptFormat::Html, "spoiler") => wrap(out, "<span class=\"spoiler\">", "</span>"),
+        _ => render(children, format, out),
+    }
+}
+
+fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '*' | '_' | '[' | ']' | '`' | '<' | '>' | '~' | '#') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
@@ -0,0 +1,40 @@
+//! F-List character names: which are valid, and how F-Chat compares them and names logs after them.
+//!
+//! A name is 1 to 20 characters of ASCII letters, digits, spaces, `-` and `_`, and doesn't start or end with a space.
+//! Names that differ only in case are the same character, and the client names a private conversation's log after
+//! the other character's name in lower case.
+use crate::error::{Error, InvalidName};
+
+pub const MAX_CHARACTER_NAME_LEN: usize = 20;
+
+/// Check `name` could be an F-List character's.
+pub fn validate_character_name(name: &str) -> Result<(), Error> {
+    let invalid = |reason: &str| Err(Error::from(InvalidName { name: name.to_string(), reason: reason.to_string() }));
+    if name.is_empty() {
+        return invalid("it's empty");
+    }
+    if name.chars().count() > MAX_CHARACTER_NAME_LEN {
+        return invalid(&format!("it's longer than {} characters", MAX_CHARACTER_NAME_LEN));
+    }
+    if let Some(c) = name.chars().find(|&c| !(c.is_ascii_alphanumeric() || c == ' ' || c == '-' || c == '_')) {
+        return invalid(&format!("it has a '{}' in it", c));
+    }
+    if name.starts_with(' ') || name.ends_with(' ') {
+        return invalid("it starts or ends with a space");
+    }
+    Ok(())
+}
+
+pub fn is_character_name(name: &str) -> bool {
+    validate_character_name(name).is_ok()
+}
+
+/// The form names are compared in, and the name of the log the client keeps private messages with `name` in.
+pub fn canonical_name(name: &str) -> String {
+    name.to_lowercase()
+}
+
+/// Whether `a` and `b` are the same character's name, going by [canonical_name](fn.canonical_name.html).
+pub fn same_character(a: &str, b: &str) -> bool {
+    a.chars().flat_map(char::to_lowercase).eq(b.chars().flat_map(char::to_lowercase))
+}
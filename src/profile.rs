@@ -0,0 +1,150 @@
+//! A character's profile in F-Chat's data directory, which keeps the character's logs in `<character>/logs`, each named
+//! after its conversation with an idx next to it.
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use crate::error::Error;
+use crate::fchat_index::FChatIndex;
+use crate::names::{canonical_name, is_character_name};
+use crate::FChatMessageReader;
+
+/// The console's log, which holds what the client itself said, like notices from the server.
+pub const CONSOLE_FILE_NAME: &str = "_";
+
+/// What a conversation is with, going by how F-Chat names its logs: `#` and the channel's id for a channel, private
+/// channels having ids starting with `adh-`, the other character's name in lower case for private messages, and `_`
+/// for the console.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum FChatConversationKind {
+    PublicChannel,
+    PrivateChannel,
+    /// Private messages with another character.
+    Private,
+    Console,
+    /// A file that isn't named like any of F-Chat's logs.
+    Unknown,
+}
+
+impl FChatConversationKind {
+    /// The kind of conversation logged in `file_name`. The idx header's name, if there is one, settles logs whose file
+    /// name doesn't, such as ones renamed by hand.
+    pub fn classify(file_name: &str, idx_name: Option<&str>) -> Self {
+        if file_name == CONSOLE_FILE_NAME {
+            return FChatConversationKind::Console;
+        }
+        if let Some(channel) = file_name.strip_prefix('#') {
+            return match channel.get(..4) {
+                Some(prefix) if prefix.eq_ignore_ascii_case("adh-") => FChatConversationKind::PrivateChannel,
+                _ => FChatConversationKind::PublicChannel,
+            };
+        }
+        if is_character_name(file_name) || idx_name.is_some_and(is_character_name) {
+            return FChatConversationKind::Private;
+        }
+        FChatConversationKind::Unknown
+    }
+}
+
+/// One conversation's log and idx in a [profile](struct.FChatProfile.html).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FChatConversation {
+    /// The log's file name.
+    pub file_name: String,
+    pub log_path: PathBuf,
+    /// Where the idx is, or would be if the log doesn't have one.
+    pub idx_path: PathBuf,
+}
+
+impl FChatConversation {
+    pub fn new<P: AsRef<Path>>(logs_dir: P, file_name: &str) -> Self {
+        let log_path = logs_dir.as_ref().join(file_name);
+        FChatConversation {
+            file_name: file_name.to_string(),
+            idx_path: logs_dir.as_ref().join(format!("{}.idx", file_name)),
+            log_path,
+        }
+    }
+
+    pub fn has_idx(&self) -> bool {
+        self.idx_path.is_file()
+    }
+
+    pub fn index(&self) -> Result<FChatIndex, Error> {
+        let idx = File::open(&self.idx_path).map_err(|err| Error::from(err).in_file(&self.idx_path))?;
+        FChatIndex::from_buf(&mut BufReader::new(idx)).map_err(|err| err.in_file(&self.idx_path))
+    }
+
+    /// What the conversation is with. Only the idx's header is read, and an idx that can't be read is ignored.
+    pub fn kind(&self) -> FChatConversationKind {
+        let idx_name = File::open(&self.idx_path).ok()
+            .and_then(|idx| FChatIndex::read_header_from_buf(&mut BufReader::new(idx)).ok())
+            .map(|index| index.name);
+        FChatConversationKind::classify(&self.file_name, idx_name.as_deref())
+    }
+
+    pub fn reader(&self) -> Result<FChatMessageReader<'static>, Error> {
+        FChatMessageReader::from_path(&self.log_path)
+    }
+}
+
+/// A character's directory in F-Chat's data directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FChatProfile {
+    pub path: PathBuf,
+}
+
+impl FChatProfile {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        FChatProfile { path: path.as_ref().to_path_buf() }
+    }
+
+    /// The character the profile belongs to, going by the directory's name.
+    pub fn character(&self) -> Option<&str> {
+        self.path.file_name().and_then(|name| name.to_str())
+    }
+
+    pub fn logs_dir(&self) -> PathBuf {
+        self.path.join("logs")
+    }
+
+    /// The conversation logged in `file_name`, whether or not it exists yet.
+    pub fn conversation(&self, file_name: &str) -> FChatConversation {
+        FChatConversation::new(self.logs_dir(), file_name)
+    }
+
+    /// The conversation logging private messages with `character`, which the client names in lower case.
+    pub fn private_conversation(&self, character: &str) -> FChatConversation {
+        self.conversation(&canonical_name(character))
+    }
+
+    /// Every log in the profile, sorted by file name. A profile without a logs directory has none.
+    pub fn conversations(&self) -> Result<Vec<FChatConversation>, Error> {
+        let logs_dir = self.logs_dir();
+        let entries = match fs::read_dir(&logs_dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(Error::from(err).in_file(&logs_dir)),
+        };
+        let mut conversations = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(|err| Error::from(err).in_file(&logs_dir))?;
+            let file_name = entry.file_name();
+            let file_name = match file_name.to_str() {
+                Some(file_name) if !file_name.ends_with(".idx") => file_name,
+                _ => continue,
+            };
+            if entry.file_type().map_err(|err| Error::from(err).in_file(entry.path()))?.is_file() {
+                conversations.push(FChatConversation::new(&logs_dir, file_name));
+            }
+        }
+        conversations.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+        Ok(conversations)
+    }
+
+    /// Every log in the profile of a conversation of `kind`, e.g. only private messages.
+    pub fn conversations_of(&self, kind: FChatConversationKind) -> Result<Vec<FChatConversation>, Error> {
+        Ok(self.conversations()?.into_iter().filter(|conversation| conversation.kind() == kind).collect())
+    }
+}
@@ -0,0 +1,93 @@
+//! Splitting a conversation into sessions, or scenes, wherever nobody said anything for longer than an idle gap.
+use std::collections::BTreeMap;
+use std::ops::Range;
+use chrono::{NaiveDateTime, TimeDelta};
+use crate::error::Error;
+use crate::fchat_message::FChatMessage;
+use crate::names::canonical_name;
+use crate::FChatMessageCursor;
+
+/// A run of messages without an idle gap in it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Session {
+    /// When the first and last messages were sent, in UTC.
+    pub first_activity: NaiveDateTime,
+    pub last_activity: NaiveDateTime,
+    /// Messages sent by each participant, by their [canonical name](../names/fn.canonical_name.html), so the same
+    /// character counts once however their name was capitalised.
+    pub participants: BTreeMap<String, u64>,
+    pub messages: u64,
+    /// Where the session's records are in the log.
+    pub offsets: Range<u64>,
+}
+
+impl Session {
+    fn start(message: &FChatMessage, offsets: Range<u64>) -> Self {
+        let mut session = Session {
+            first_activity: message.datetime,
+            last_activity: message.datetime,
+            participants: BTreeMap::new(),
+            messages: 0,
+            offsets,
+        };
+        session.count(message);
+        session
+    }
+
+    fn count(&mut self, message: &FChatMessage) {
+        *self.participants.entry(canonical_name(&message.sender)).or_insert(0) += 1;
+        self.messages += 1;
+    }
+
+    pub fn duration(&self) -> TimeDelta {
+        self.last_activity - self.first_activity
+    }
+}
+
+/// Groups messages into [sessions](struct.Session.html) as they're read. A message more than `idle_gap` after the latest
+/// one so far starts a new session.
+#[derive(Clone, Debug)]
+pub struct SessionDetector {
+    pub idle_gap: TimeDelta,
+    current: Option<Session>,
+}
+
+impl SessionDetector {
+    pub fn new(idle_gap: TimeDelta) -> Self {
+        Self {
+            idle_gap,
+            current: None,
+        }
+    }
+
+    /// Add the message whose record spans `offsets`. Returns the session it ended, if it started a new one.
+    pub fn add(&mut self, message: &FChatMessage, offsets: Range<u64>) -> Option<Session> {
+        if let Some(session) = &mut self.current {
+            if message.datetime - session.last_activity <= self.idle_gap {
+                session.last_activity = session.last_activity.max(message.datetime);
+                session.offsets.end = offsets.end;
+                session.count(message);
+                return None;
+            }
+        }
+        self.current.replace(Session::start(message, offsets))
+    }
+
+    /// The session still being added to, if there were any messages.
+    pub fn finish(self) -> Option<Session> {
+        self.current
+    }
+
+    /// Every session from the cursor's position to the end of the log.
+    pub fn sessions(cursor: &mut FChatMessageCursor, idle_gap: TimeDelta) -> Result<Vec<Session>, Error> {
+        let mut detector = Self::new(idle_gap);
+        let mut sessions = Vec::new();
+        while let Some(result) = cursor.next_message() {
+            let (offset, message) = result?;
+            sessions.extend(detector.add(&message, offset..cursor.position()));
+        }
+        sessions.extend(detector.finish());
+        Ok(sessions)
+    }
+}
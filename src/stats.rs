@@ -0,0 +1,228 @@
+//! Statistics about a conversation, built from any iterator of [messages](../fchat_message/struct.FChatMessage.html).
+//! An [activity calendar](struct.ActivityCalendar.html) only needs the index, so it's cheap enough to build for every
+//! conversation in a profile. With the `serde` feature the results can be serialised, e.g. as JSON for a dashboard.
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use chrono::{NaiveDate, NaiveDateTime, Timelike};
+use crate::error::Error;
+use crate::fchat_index::{DayBoundary, FChatIndex};
+use crate::fchat_message::{FChatMessage, FChatMessageKind};
+
+/// Counts for one sender, or for a whole conversation.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SenderStats {
+    pub messages: u64,
+    /// Whitespace separated words in the message text.
+    pub words: u64,
+    /// Characters, not bytes, in the message text.
+    pub characters: u64,
+    /// Messages of each type.
+    pub types: BTreeMap<FChatMessageKind, u64>,
+}
+
+impl SenderStats {
+    fn add(&mut self, message: &FChatMessage) {
+        let text = message.body.text();
+        self.messages += 1;
+        self.words += text.split_whitespace().count() as u64;
+        self.characters += text.chars().count() as u64;
+        *self.types.entry(message.body.kind()).or_insert(0) += 1;
+    }
+
+    /// Characters per message, or `None` without any messages.
+    pub fn average_message_length(&self) -> Option<f64> {
+        if self.messages == 0 {
+            None
+        } else {
+            Some(self.characters as f64 / self.messages as f64)
+        }
+    }
+}
+
+/// Statistics for a conversation. Days and hours are counted in the timezone of `day_boundary`.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConversationStats {
+    /// Counts over every message.
+    pub total: SenderStats,
+    pub senders: BTreeMap<String, SenderStats>,
+    /// When the first and last messages were sent, in UTC.
+    pub first_activity: Option<NaiveDateTime>,
+    pub last_activity: Option<NaiveDateTime>,
+    /// Messages sent on each day.
+    pub days: BTreeMap<NaiveDate, u64>,
+    /// Messages sent in each hour of the day.
+    pub hours: [u64; 24],
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub day_boundary: DayBoundary,
+}
+
+impl ConversationStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_day_boundary(day_boundary: DayBoundary) -> Self {
+        Self {
+            day_boundary,
+            ..Self::default()
+        }
+    }
+
+    /// Count one more message.
+    pub fn add(&mut self, message: &FChatMessage) {
+        self.total.add(message);
+        self.senders.entry(message.sender.clone()).or_default().add(message);
+        self.first_activity = Some(self.first_activity.map_or(message.datetime, |first| first.min(message.datetime)));
+        self.last_activity = Some(self.last_activity.map_or(message.datetime, |last| last.max(message.datetime)));
+        let wall_clock = self.day_boundary.wall_clock(&message.utc_datetime());
+        *self.days.entry(wall_clock.date()).or_insert(0) += 1;
+        self.hours[wall_clock.hour() as usize] += 1;
+    }
+
+    pub fn from_messages<'a, I: IntoIterator<Item = &'a FChatMessage>>(messages: I, day_boundary: DayBoundary) -> Self {
+        let mut stats = Self::with_day_boundary(day_boundary);
+        for message in messages {
+            stats.add(message);
+        }
+        stats
+    }
+
+    /// Like [from_messages](#method.from_messages), but for a reader, stopping at the first error.
+    pub fn from_reader<I: IntoIterator<Item = Result<FChatMessage, Error>>>(messages: I, day_boundary: DayBoundary) -> Result<Self, Error> {
+        let mut stats = Self::with_day_boundary(day_boundary);
+        for message in messages {
+            stats.add(&message?);
+        }
+        Ok(stats)
+    }
+
+    /// Up to `count` days with the most messages, busiest first. Ties go to the earlier day.
+    pub fn busiest_days(&self, count: usize) -> Vec<(NaiveDate, u64)> {
+        let mut days: Vec<(NaiveDate, u64)> = self.days.iter().map(|(date, messages)| (*date, *messages)).collect();
+        days.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        days.truncate(count);
+        days
+    }
+
+    /// Up to `count` hours of the day with the most messages, busiest first. Hours without messages are left out.
+    pub fn busiest_hours(&self, count: usize) -> Vec<(u32, u64)> {
+        let mut hours: Vec<(u32, u64)> = (0..24).map(|hour| (hour, self.hours[hour as usize]))
+            .filter(|(_, messages)| *messages > 0)
+            .collect();
+        hours.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hours.truncate(count);
+        hours
+    }
+
+    /// Characters per message over the whole conversation.
+    pub fn average_message_length(&self) -> Option<f64> {
+        self.total.average_message_length()
+    }
+}
+
+/// A day with activity, according to the index.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ActiveDay {
+    pub date: NaiveDate,
+    /// Where the day starts in the log.
+    pub offset: u64,
+    /// Bytes the day takes up in the log, which is roughly how much was said.
+    pub bytes: u64,
+}
+
+/// An inclusive run of days.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DateSpan {
+    pub first: NaiveDate,
+    pub last: NaiveDate,
+}
+
+impl DateSpan {
+    /// How many days the span covers.
+    pub fn days(&self) -> u64 {
+        (self.last - self.first).num_days() as u64 + 1
+    }
+}
+
+/// The days a conversation was active on, built from its index without reading any messages.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ActivityCalendar {
+    /// Active days, in order.
+    pub days: Vec<ActiveDay>,
+}
+
+impl ActivityCalendar {
+    /// Each day's volume is the distance to the next day's offset, and the last day runs to `log_len`.
+    pub fn from_index(index: &FChatIndex, log_len: u64) -> Self {
+        let days = index.offsets.iter().enumerate().map(|(n, offset)| {
+            let end = index.offsets.get(n + 1).map_or(log_len, |next| next.offset);
+            ActiveDay {
+                date: offset.date,
+                offset: offset.offset,
+                bytes: end.saturating_sub(offset.offset),
+            }
+        }).collect();
+        Self { days }
+    }
+
+    /// Read the idx at `idx_path`, taking the log's length from the file system. Errors carry the path they came from.
+    pub fn from_files<P: AsRef<Path>, Q: AsRef<Path>>(log_path: P, idx_path: Q) -> Result<Self, Error> {
+        let log_len = std::fs::metadata(&log_path).map_err(|err| Error::from(err).in_file(&log_path))?.len();
+        let idx = File::open(&idx_path).map_err(|err| Error::from(err).in_file(&idx_path))?;
+        let index = FChatIndex::from_buf(&mut BufReader::new(idx)).map_err(|err| err.in_file(&idx_path))?;
+        Ok(Self::from_index(&index, log_len))
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.days.iter().map(|day| day.bytes).sum()
+    }
+
+    pub fn day(&self, date: NaiveDate) -> Option<&ActiveDay> {
+        self.days.binary_search_by(|day| day.date.cmp(&date)).ok().map(|n| &self.days[n])
+    }
+
+    /// Runs of consecutive active days.
+    pub fn streaks(&self) -> Vec<DateSpan> {
+        let mut streaks: Vec<DateSpan> = Vec::new();
+        for day in &self.days {
+            match streaks.last_mut() {
+                Some(streak) if streak.last.succ_opt() == Some(day.date) => streak.last = day.date,
+                _ => streaks.push(DateSpan { first: day.date, last: day.date }),
+            }
+        }
+        streaks
+    }
+
+    /// Runs of inactive days between the first and last active day.
+    pub fn gaps(&self) -> Vec<DateSpan> {
+        self.days.windows(2).filter_map(|pair| {
+            let first = pair[0].date.succ_opt()?;
+            let last = pair[1].date.pred_opt()?;
+            if first <= last { Some(DateSpan { first, last }) } else { None }
+        }).collect()
+    }
+
+    /// The longest streak, the earliest one if there's a tie.
+    pub fn longest_streak(&self) -> Option<DateSpan> {
+        longest(self.streaks())
+    }
+
+    /// The longest gap, the earliest one if there's a tie.
+    pub fn longest_gap(&self) -> Option<DateSpan> {
+        longest(self.gaps())
+    }
+}
+
+fn longest(spans: Vec<DateSpan>) -> Option<DateSpan> {
+    spans.into_iter().fold(None, |longest, span| match longest {
+        Some(longest) if longest.days() >= span.days() => Some(longest),
+        _ => Some(span),
+    })
+}
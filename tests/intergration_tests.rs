@@ -1,18 +1,28 @@
-use chrono::{Local};
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, TimeDelta, Timelike};
 use std::error;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::prelude::*;
 use std::io::SeekFrom;
 use tempdir::TempDir;
-use byteorder;
+use regex::Regex;
 use byteorder::{ReadBytesExt};
-use std::io::{BufReader};
+use std::io::{BufReader, Cursor};
 const DIR_NAME: &str = "fchat3-log-lib-tests";
 const TEST_CONTENTS: &[u8] = include_bytes!("carlen white");
 const TEST_INDEX: &[u8] = include_bytes!("carlen white.idx");
-use fchat3_log_lib::fchat_message::{FChatMessage, FChatMessageType};
-use fchat3_log_lib::{FChatMessageReader, FChatWriter};
+use fchat3_log_lib::fchat_message::{max_body_len, FChatMessage, FChatMessageKind, FChatMessageType, FChatReadOptions, FChatUnknownTypePolicy};
+use fchat3_log_lib::fchat_index::{DayBoundary, FChatIndex, FChatIndexOffset};
+use fchat3_log_lib::error::Error;
+use fchat3_log_lib::anonymise::FChatAnonymiser;
+use fchat3_log_lib::container::{BlockCodec, FChatContainer};
+use fchat3_log_lib::dedup::FChatDeduplicator;
+use fchat3_log_lib::diff::{LogDiff, LogDifference};
+use fchat3_log_lib::names::{canonical_name, same_character, validate_character_name};
+use fchat3_log_lib::manuscript::{bbcode_to_html, write_manuscript, ManuscriptFormat, ManuscriptOptions};
+use fchat3_log_lib::sessions::SessionDetector;
+use fchat3_log_lib::stats::{ActivityCalendar, ConversationStats, DateSpan};
+use fchat3_log_lib::{log_tail_is_intact, FChatMessageCursor, FChatMessageReader, FChatMessageReaderReversed, FChatWriter, FChatWriterOptions, FChatWriterOrdering};
 
 type BoxedError = Box<dyn error::Error>;
 
@@ -26,7 +36,7 @@ fn create_test_file(dir: &TempDir, name: &str, contents: &[u8]) -> Result<std::f
     let mut options = OpenOptions::new();
     options.read(true).write(true).create(true);
     let mut file = options.open(file_path_read)?;
-    file.write(contents)?;
+    file.write_all(contents)?;
     file.seek(SeekFrom::Start(0))?;
     Ok(file)
 }
@@ -67,7 +77,7 @@ fn create_and_read_basic() -> Result<(), BoxedError> {
     f.seek(SeekFrom::Start(0))?;
     let message = FChatMessage::read_from_buf(&mut f)?;
     println!("Read\n{:?}", message);
-    assert_eq!(temp_datetime.timestamp(), message.datetime.timestamp());
+    assert_eq!(temp_datetime.and_utc().timestamp(), message.datetime.and_utc().timestamp());
     assert_eq!(temp_body.to_string(), message.body.to_string());
     assert_eq!(temp_sender, message.sender);
     dir.close()?;
@@ -82,7 +92,7 @@ fn can_create_1_to_1_from_native() -> Result<(), BoxedError> {
     options.read(true).write(true).create(true);
     let mut f_w = options.open(file_path_write)?;
     let size = f_r.metadata()?.len();
-    while size > f_r.seek(SeekFrom::Current(0))? {
+    while size > f_r.stream_position()? {
         let message = FChatMessage::read_from_buf(&mut f_r)?;
         message.write_to_buf(&mut f_w)?;
     }
@@ -90,14 +100,14 @@ fn can_create_1_to_1_from_native() -> Result<(), BoxedError> {
     assert_eq!(TEST_CONTENTS.len(), f_w.metadata()?.len() as usize);
     let mut i: u64 = 0;
     loop {
-        if size <= f_w.seek(SeekFrom::Current(0))? {
+        if size <= f_w.stream_position()? {
             break;
         }
         let written_byte = f_w.read_u8()?;
         let source_byte = TEST_CONTENTS[i as usize];
         assert_eq!(written_byte, source_byte);
         //println!("Byte {} OK! ({})", i, written_byte);
-        i = i + 1;
+        i += 1;
     }
     /*
     f_w.seek(SeekFrom::Start(0))?;
@@ -193,4 +203,1313 @@ fn can_write_using_writer() -> Result<(), BoxedError> {
     check_index(writer)?;
     dir.close()?;
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Accepts at most three bytes per write, like a pipe or socket that's full.
+struct ShortWriter(Vec<u8>);
+
+impl Write for ShortWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(3);
+        self.0.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn short_writes_still_write_whole_records() -> Result<(), BoxedError> {
+    let mut out = ShortWriter(Vec::new());
+    for message in FChatMessageReader::new(TEST_CONTENTS) {
+        message?.write_to_buf(&mut out)?;
+    }
+    assert_eq!(TEST_CONTENTS, out.0.as_slice());
+    let mut out = ShortWriter(Vec::new());
+    FChatIndex::from_buf(&mut Cursor::new(TEST_INDEX))?.write_header_to_buf(&mut out)?;
+    assert_eq!(&TEST_INDEX[..out.0.len()], out.0.as_slice());
+    assert_eq!(TEST_INDEX[0] as usize + 1, out.0.len());
+    Ok(())
+}
+
+#[test]
+fn cursor_steps_both_ways() -> Result<(), BoxedError> {
+    let dir = create_dir()?;
+    let log_fd = create_test_file(&dir, "1", TEST_CONTENTS)?;
+    let idx_fd = create_test_file(&dir, "1.idx", TEST_INDEX)?;
+    let index = FChatIndex::from_buf(&mut &idx_fd)?;
+    let mut cursor = FChatMessageCursor::from_index_offset(&log_fd, &index.offsets[0])?;
+    let (first_offset, first) = cursor.next_message().unwrap()?;
+    let (second_offset, second) = cursor.next_message().unwrap()?;
+    assert_eq!(0, first_offset);
+    assert_eq!(26, second_offset);
+    assert_eq!(TEST_CONTENTS.len() as u64, cursor.position());
+    assert!(cursor.next_message().is_none());
+    let (offset, message) = cursor.previous_message().unwrap()?;
+    assert_eq!(second_offset, offset);
+    assert_eq!(second.sender, message.sender);
+    let (offset, message) = cursor.previous_message().unwrap()?;
+    assert_eq!(first_offset, offset);
+    assert_eq!(first.body.to_string(), message.body.to_string());
+    assert!(cursor.previous_message().is_none());
+    cursor.seek_to(second_offset)?;
+    assert_eq!(second.sender, cursor.next_message().unwrap()?.1.sender);
+    assert!(cursor.seek_to(TEST_CONTENTS.len() as u64 + 1).is_err());
+    dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn read_using_reversed_reader() -> Result<(), BoxedError> {
+    let forward: Vec<FChatMessage> = FChatMessageReader::new(TEST_CONTENTS).collect::<Result<_, _>>()?;
+    let reader = FChatMessageReaderReversed::new(Cursor::new(TEST_CONTENTS))?;
+    let backward: Vec<FChatMessage> = reader.collect::<Result<_, _>>()?;
+    assert_eq!(forward.len(), backward.len());
+    for (a, b) in forward.iter().zip(backward.iter().rev()) {
+        assert_eq!(a.datetime, b.datetime);
+        assert_eq!(a.sender, b.sender);
+        assert_eq!(a.body.to_string(), b.body.to_string());
+    }
+    let mut reader = FChatMessageReaderReversed::from_offset(Cursor::new(TEST_CONTENTS), 26)?;
+    assert_eq!(forward[0].sender, reader.next().unwrap()?.sender);
+    assert!(reader.next().is_none());
+    Ok(())
+}
+
+#[test]
+fn reversed_reader_from_end_of_day() -> Result<(), BoxedError> {
+    let dir = create_dir()?;
+    let log_fd = create_test_file(&dir, "1", TEST_CONTENTS)?;
+    let index = FChatIndex::from_buf(&mut Cursor::new(TEST_INDEX))?;
+    let day = index.offsets[0].date;
+    let reader = FChatMessageReaderReversed::from_end_of_day(&log_fd, &index, day)?;
+    assert_eq!(2, reader.count());
+    let mut reader = FChatMessageReaderReversed::from_end_of_day(&log_fd, &index, day.pred_opt().unwrap())?;
+    assert!(reader.next().is_none());
+    dir.close()?;
+    Ok(())
+}
+
+const DAY: i64 = 86400;
+/// Midnight UTC of a day in 2020, for tests that lay messages out over days.
+const DAY_START: i64 = 1_600_000_000 - 1_600_000_000 % DAY;
+
+fn message_at(timestamp: i64, body: &str) -> FChatMessage {
+    FChatMessage {
+        datetime: DateTime::from_timestamp(timestamp, 0).unwrap().naive_utc(),
+        sender: String::from("Someone"),
+        body: FChatMessageType::Message(String::from(body)),
+    }
+}
+
+#[test]
+fn writer_ordering_policies() -> Result<(), BoxedError> {
+    let start = DAY_START;
+    let mut writer = FChatWriter::new(Cursor::new(Vec::new()), Cursor::new(Vec::new()), "Test!".to_string())?;
+    writer.write_message(message_at(start + DAY, "second day"))?;
+    match writer.write_message(message_at(start, "first day")) {
+        Err(Error::OutOfOrderError(_)) => {}
+        other => panic!("expected an out of order error, got {:?}", other),
+    }
+    writer.options.ordering = FChatWriterOrdering::Clamp;
+    writer.write_message(message_at(start, "clamped"))?;
+    writer.options.ordering = FChatWriterOrdering::Allow;
+    writer.write_message(message_at(start, "kept"))?;
+    assert_eq!(1, writer.index.offsets.len());
+    assert_eq!(Some(message_at(start + DAY, "").datetime), writer.last_datetime());
+    writer.log_buf.seek(SeekFrom::Start(0))?;
+    let messages: Vec<FChatMessage> = FChatMessageReader::new(&mut writer.log_buf).collect::<Result<_, _>>()?;
+    assert_eq!(3, messages.len());
+    assert_eq!(messages[0].datetime, messages[1].datetime);
+    assert_eq!("kept", messages[2].body.to_string());
+    assert!(messages[2].datetime < messages[1].datetime);
+    Ok(())
+}
+
+#[test]
+fn regenerated_idx_only_moves_forwards() -> Result<(), BoxedError> {
+    let start = DAY_START;
+    let dir = create_dir()?;
+    let log_fd = create_test_file(&dir, "1", &[])?;
+    let idx_fd = create_test_file(&dir, "1.idx", &[])?;
+    let mut writer = FChatWriter::new(&log_fd, &idx_fd, "Test!".to_string())?;
+    writer.options.ordering = FChatWriterOrdering::Allow;
+    for day in [1, 0, 2] {
+        writer.write_message(message_at(start + day * DAY, "hi"))?;
+    }
+    let first_day = DateTime::from_timestamp(start, 0).unwrap().date_naive();
+    let days = |index: &FChatIndex| index.offsets.iter().map(|offset| ((offset.date - first_day).num_days(), offset.offset)).collect::<Vec<_>>();
+    let written = days(&writer.index);
+    drop(writer);
+
+    // The day before the last indexed one doesn't get an entry of its own, however the idx is built.
+    assert_eq!(vec![(1, 0), (2, 2 * message_at(0, "hi").bytes_used() + 4)], written);
+    (&log_fd).seek(SeekFrom::Start(0))?;
+    (&idx_fd).seek(SeekFrom::Start(0))?;
+    FChatWriter::regenerate_idx(&log_fd, &idx_fd)?;
+    let index = FChatIndex::from_buf(&mut &idx_fd)?;
+    assert_eq!(written, days(&index));
+    assert!(index.matches_log(&mut &log_fd, DayBoundary::Utc)?);
+    dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn batch_write_and_recover() -> Result<(), BoxedError> {
+    let start = DAY_START;
+    let dir = create_dir()?;
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).create(true);
+    let log_fd = options.open(dir.path().join("1"))?;
+    let idx_fd = options.open(dir.path().join("1.idx"))?;
+    {
+        let mut writer = FChatWriter::new(&log_fd, &idx_fd, "Test!".to_string())?.sync_to(&log_fd, &idx_fd)?;
+        writer.write_batch(vec![message_at(start, "one"), message_at(start + 10, "two"), message_at(start + DAY, "three")])?;
+        assert_eq!(2, writer.index.offsets.len());
+        assert!(writer.write_batch(vec![message_at(start + DAY, "four"), message_at(start, "five")]).is_err());
+        assert_eq!(2, writer.index.offsets.len());
+    }
+    let good_log_len = log_fd.metadata()?.len();
+    let good_idx_len = idx_fd.metadata()?.len();
+    (&log_fd).seek(SeekFrom::Start(0))?;
+    assert_eq!(3, FChatMessageReader::new(&log_fd).count());
+
+    // A whole record for a new day that never made it into the idx, then a torn record and a torn idx entry.
+    let mut record = Vec::new();
+    message_at(start + 2 * DAY, "six").write_to_buf(&mut record)?;
+    (&log_fd).seek(SeekFrom::End(0))?;
+    (&log_fd).write_all(&record)?;
+    (&log_fd).write_all(&record[..record.len() - 3])?;
+    (&idx_fd).seek(SeekFrom::End(0))?;
+    (&idx_fd).write_all(&[1, 2, 3])?;
+
+    let recovery = FChatWriter::recover(&log_fd, &idx_fd)?;
+    assert_eq!(record.len() as u64 - 3, recovery.log_bytes_removed);
+    assert_eq!(3, recovery.idx_bytes_removed);
+    assert_eq!(1, recovery.idx_entries_added);
+    assert_eq!(good_log_len + record.len() as u64, log_fd.metadata()?.len());
+    assert_eq!(good_idx_len + 7, idx_fd.metadata()?.len());
+    let writer = FChatWriter::from_idx(&log_fd, &idx_fd)?;
+    assert_eq!(3, writer.index.offsets.len());
+    assert_eq!(good_log_len, writer.index.offsets[2].offset);
+    check_index(writer)?;
+
+    // A stale idx entry pointing past the end of the log doesn't hide a torn record.
+    let recovered_log_len = log_fd.metadata()?.len();
+    let recovered_idx_len = idx_fd.metadata()?.len();
+    (&log_fd).seek(SeekFrom::End(0))?;
+    (&log_fd).write_all(&record[..5])?;
+    let stale = FChatIndexOffset { date: message_at(start + 3 * DAY, "").datetime.date(), offset: recovered_log_len + 100 };
+    (&idx_fd).seek(SeekFrom::End(0))?;
+    stale.write_to_buf(&mut &idx_fd)?;
+    let recovery = FChatWriter::recover(&log_fd, &idx_fd)?;
+    assert_eq!((5, 7, 0), (recovery.log_bytes_removed, recovery.idx_bytes_removed, recovery.idx_entries_added));
+    assert_eq!((recovered_log_len, recovered_idx_len), (log_fd.metadata()?.len(), idx_fd.metadata()?.len()));
+    check_index(FChatWriter::from_idx(&log_fd, &idx_fd)?)?;
+    dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn open_creates_validates_and_regenerates() -> Result<(), BoxedError> {
+    let start = DAY_START;
+    let dir = create_dir()?;
+    let log_path = dir.path().join("someone");
+    let idx_path = dir.path().join("someone.idx");
+    {
+        let mut writer = FChatWriter::open(&log_path, &idx_path, "Someone".to_string())?;
+        writer.write_batch(vec![message_at(start, "one"), message_at(start + DAY, "two")])?;
+    }
+    let good_idx = std::fs::read(&idx_path)?;
+    {
+        let mut writer = FChatWriter::open(&log_path, &idx_path, "Ignored".to_string())?;
+        assert_eq!("Someone", writer.index.name);
+        assert_eq!(2, writer.index.offsets.len());
+        writer.write_message(message_at(start + 2 * DAY, "three"))?;
+    }
+    // Drop the last day from the idx so it's stale.
+    let mut options = OpenOptions::new();
+    options.write(true);
+    options.open(&idx_path)?.set_len(good_idx.len() as u64)?;
+    {
+        let writer = FChatWriter::open(&log_path, &idx_path, "Ignored".to_string())?;
+        assert_eq!(3, writer.index.offsets.len());
+        check_index(writer)?;
+    }
+    std::fs::remove_file(&idx_path)?;
+    {
+        let writer = FChatWriter::open(&log_path, &idx_path, "Recreated".to_string())?;
+        assert_eq!("Recreated", writer.index.name);
+        assert_eq!(3, writer.index.offsets.len());
+        assert_eq!(Some(message_at(start + 2 * DAY, "").datetime), writer.last_datetime());
+    }
+    dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn day_boundary_splits_index_days() -> Result<(), BoxedError> {
+    const HOUR: i64 = 3600;
+    let start = DAY_START;
+    let messages = vec![message_at(start + 13 * HOUR, "late evening"), message_at(start + 15 * HOUR, "after midnight")];
+    let plus_ten = FixedOffset::east_opt(10 * HOUR as i32).unwrap();
+    assert_eq!(10, messages[0].datetime_in(&plus_ten).hour() - messages[0].utc_datetime().hour());
+
+    let mut utc_writer = FChatWriter::new(Cursor::new(Vec::new()), Cursor::new(Vec::new()), "Test!".to_string())?;
+    utc_writer.write_batch(messages.clone())?;
+    assert_eq!(1, utc_writer.index.offsets.len());
+
+    let mut writer = FChatWriter::new(Cursor::new(Vec::new()), Cursor::new(Vec::new()), "Test!".to_string())?;
+    writer.options.day_boundary = DayBoundary::Offset(plus_ten);
+    writer.write_batch(messages.clone())?;
+    assert_eq!(2, writer.index.offsets.len());
+    let second_day = &writer.index.offsets[1];
+    assert_eq!(messages[0].datetime.date().succ_opt().unwrap(), second_day.date);
+    assert_eq!(start + 14 * HOUR, second_day.start(DayBoundary::Offset(plus_ten)).timestamp());
+    let found = writer.index.offset_for(&messages[1].utc_datetime(), DayBoundary::Offset(plus_ten)).unwrap();
+    assert_eq!(second_day.offset, found.offset);
+
+    writer.log_buf.seek(SeekFrom::Start(0))?;
+    assert!(writer.index.matches_log(&mut writer.log_buf, DayBoundary::Offset(plus_ten))?);
+    assert!(!writer.index.matches_log(&mut writer.log_buf, DayBoundary::Utc)?);
+    Ok(())
+}
+
+#[test]
+fn writer_splits_long_messages() -> Result<(), BoxedError> {
+    let text = "Ünïcödé [b]bold[/b] [url=https://f-list.net]link[/url] ".repeat(3000);
+    let message = FChatMessage {
+        datetime: DateTime::from_timestamp(1_600_000_000, 0).unwrap().naive_utc(),
+        sender: String::from("Someone"),
+        body: FChatMessageType::Action(text.clone()),
+    };
+    let mut writer = FChatWriter::new(Cursor::new(Vec::new()), Cursor::new(Vec::new()), "Test!".to_string())?;
+    match writer.write_message(message.clone()) {
+        Err(Error::MessageTooLongError(err)) => assert_eq!(text.len(), err.length),
+        other => panic!("expected a message too long error, got {:?}", other),
+    }
+    writer.options.split_long_messages = true;
+    writer.write_message(message.clone())?;
+    writer.log_buf.seek(SeekFrom::Start(0))?;
+    let parts: Vec<FChatMessage> = FChatMessageReader::new(&mut writer.log_buf).collect::<Result<_, _>>()?;
+    assert!(parts.len() > 1);
+    let mut joined = String::new();
+    for part in &parts {
+        let part_text = part.body.text();
+        assert!(part_text.len() <= max_body_len(part.sender.len()));
+        assert_eq!(part_text.matches('[').count(), part_text.matches(']').count());
+        assert!(matches!(part.body, FChatMessageType::Action(_)));
+        joined.push_str(part_text);
+    }
+    assert_eq!(text, joined);
+
+    let mut bad_sender = message;
+    bad_sender.sender = "x".repeat(300);
+    assert!(matches!(writer.write_message(bad_sender), Err(Error::InvalidSenderError(_))));
+    Ok(())
+}
+
+#[test]
+fn reader_errors_carry_location() -> Result<(), BoxedError> {
+    let mut corrupted = TEST_CONTENTS.to_vec();
+    corrupted[26 + 6] = 0xff;
+    let dir = create_dir()?;
+    let path = dir.path().join("corrupted");
+    std::fs::write(&path, &corrupted)?;
+    let mut reader = FChatMessageReader::from_path(&path)?;
+    reader.next().unwrap()?;
+    let err = reader.next().unwrap().unwrap_err();
+    assert!(matches!(err.root(), Error::UTF8ConversionError(_)));
+    assert_eq!(Some(26), err.offset());
+    assert_eq!(Some(1), err.record());
+    assert_eq!(Some(path.as_path()), err.path());
+    assert!(err.to_string().contains("at byte 26, record 1"));
+    assert!(error::Error::source(&err).is_none());
+
+    let truncated = &TEST_CONTENTS[..TEST_CONTENTS.len() - 3];
+    let results: Vec<_> = FChatMessageReader::new(truncated).collect();
+    assert_eq!(2, results.len());
+    assert!(matches!(results[1].as_ref().unwrap_err().root(), Error::TruncatedError(_)));
+    let source = error::Error::source(results[1].as_ref().unwrap_err()).unwrap();
+    assert!(!results[1].as_ref().unwrap_err().to_string().contains(&source.to_string()));
+    let cut_at_boundary = &TEST_CONTENTS[..26];
+    assert_eq!(1, FChatMessageReader::new(cut_at_boundary).collect::<Result<Vec<_>, _>>()?.len());
+    let torn_timestamp = &TEST_CONTENTS[..28];
+    assert!(matches!(FChatMessageReader::new(torn_timestamp).nth(1).unwrap().unwrap_err().root(), Error::TruncatedError(_)));
+    dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn torn_tail_is_detected_and_trimmed() -> Result<(), BoxedError> {
+    let mut torn = TEST_CONTENTS.to_vec();
+    torn.extend_from_slice(&TEST_CONTENTS[26..TEST_CONTENTS.len() - 5]);
+    assert!(log_tail_is_intact(&mut Cursor::new(TEST_CONTENTS))?);
+    assert!(!log_tail_is_intact(&mut Cursor::new(&torn))?);
+
+    let dir = create_dir()?;
+    let log_fd = create_test_file(&dir, "1", &torn)?;
+    let idx_fd = create_test_file(&dir, "1.idx", TEST_INDEX)?;
+    let err = FChatWriter::from_idx(&log_fd, &idx_fd).err().unwrap();
+    assert!(matches!(err.root(), Error::TruncatedError(_)));
+    assert_eq!(Some(TEST_CONTENTS.len() as u64), err.offset());
+
+    let log_path = dir.path().join("1");
+    let idx_path = dir.path().join("1.idx");
+    let err = FChatWriter::open(&log_path, &idx_path, "Ignored".to_string()).err().unwrap();
+    assert!(matches!(err.root(), Error::TruncatedError(_)));
+    assert_eq!(Some(log_path.as_path()), err.path());
+    let options = FChatWriterOptions { trim_torn_tail: true, ..FChatWriterOptions::default() };
+    let mut writer = FChatWriter::open_with_options(&log_path, &idx_path, "Ignored".to_string(), options)?;
+    assert_eq!(TEST_CONTENTS.len() as u64, log_fd.metadata()?.len());
+    writer.write_message(message_at(1_600_000_000, "after the trim"))?;
+    drop(writer);
+    (&log_fd).seek(SeekFrom::Start(0))?;
+    assert_eq!(3, FChatMessageReader::new(&log_fd).collect::<Result<Vec<_>, _>>()?.len());
+    dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn lossy_utf8_decoding() -> Result<(), BoxedError> {
+    let mut invalid = TEST_CONTENTS.to_vec();
+    invalid[12] = 0xff;
+    let strict = FChatMessageReader::new(invalid.as_slice()).next().unwrap();
+    assert!(matches!(strict.unwrap_err().root(), Error::UTF8ConversionError(_)));
+
+    let options = FChatReadOptions { lossy_utf8: true, ..FChatReadOptions::default() };
+    let mut reader = FChatMessageReader::new(invalid.as_slice()).with_options(options);
+    let mut messages = Vec::new();
+    let mut lossy = Vec::new();
+    while let Some(message) = reader.next() {
+        messages.push(message?);
+        lossy.push(reader.lossy());
+    }
+    assert_eq!(vec![true, false], lossy);
+    assert_eq!("\u{FFFD}ello World!", messages[0].body.text());
+    let reversed: Vec<FChatMessage> = FChatMessageReaderReversed::new(Cursor::new(&invalid))?.with_options(options).collect::<Result<_, _>>()?;
+    assert_eq!(messages[0].body.text(), reversed[1].body.text());
+
+    let dir = create_dir()?;
+    let log_fd = create_test_file(&dir, "1", &invalid)?;
+    let idx_fd = create_test_file(&dir, "1.idx", TEST_INDEX)?;
+    FChatWriter::regenerate_idx(&log_fd, &idx_fd)?;
+    let writer = FChatWriter::from_idx(&log_fd, &idx_fd)?;
+    assert_eq!(1, writer.index.offsets.len());
+    dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn unknown_message_types() -> Result<(), BoxedError> {
+    let mut newer = TEST_CONTENTS.to_vec();
+    newer[4] = 9;
+    let messages: Vec<FChatMessage> = FChatMessageReader::new(newer.as_slice()).collect::<Result<_, _>>()?;
+    assert_eq!(2, messages.len());
+    assert!(matches!(&messages[0].body, FChatMessageType::Unknown(9, text) if text == "Hello World!"));
+    let mut written = Vec::new();
+    for message in &messages {
+        message.write_to_buf(&mut written)?;
+    }
+    assert_eq!(newer, written);
+
+    let skip = FChatReadOptions { unknown_types: FChatUnknownTypePolicy::Skip, ..FChatReadOptions::default() };
+    let kept: Vec<FChatMessage> = FChatMessageReader::new(newer.as_slice()).with_options(skip).collect::<Result<_, _>>()?;
+    assert_eq!(1, kept.len());
+    assert_eq!("Carlen White", kept[0].sender);
+    let mut cursor = FChatMessageCursor::new(Cursor::new(&newer))?.with_options(skip);
+    cursor.seek_to_end()?;
+    assert_eq!(26, cursor.previous_message().unwrap()?.0);
+    assert!(cursor.previous_message().is_none());
+
+    let error = FChatReadOptions { unknown_types: FChatUnknownTypePolicy::Error, ..FChatReadOptions::default() };
+    let mut reader = FChatMessageReader::new(newer.as_slice()).with_options(error);
+    let err = reader.next().unwrap().unwrap_err();
+    assert!(matches!(err.root(), Error::UnknownMessageTypeError(_)));
+    assert_eq!((Some(0), Some(0)), (err.offset(), err.record()));
+    assert_eq!(26, reader.position());
+    assert_eq!("Carlen White", reader.next().unwrap()?.sender);
+    let mut cursor = FChatMessageCursor::new(Cursor::new(&newer))?.with_options(error);
+    assert!(cursor.next_message().unwrap().is_err());
+    assert_eq!(26, cursor.next_message().unwrap()?.0);
+    assert!(cursor.next_message().is_none());
+    let mut newer_last = TEST_CONTENTS.to_vec();
+    newer_last[26 + 4] = 9;
+    let reversed: Vec<_> = FChatMessageReaderReversed::new(Cursor::new(&newer_last))?.with_options(error).collect();
+    assert_eq!(2, reversed.len());
+    assert!(matches!(reversed[0].as_ref().unwrap_err().root(), Error::UnknownMessageTypeError(_)));
+    assert_eq!("Hello World!", reversed[1].as_ref().unwrap().body.text());
+    Ok(())
+}
+
+#[test]
+fn conversation_stats() -> Result<(), BoxedError> {
+    let start = DAY_START;
+    let mut action = message_at(start + 3600 * 5, "waves hello");
+    action.sender = String::from("Other");
+    action.body = FChatMessageType::Action(String::from("waves hello"));
+    let messages = vec![
+        message_at(start + 3600 * 4, "hi there"),
+        message_at(start + 3600 * 4 + 60, "héllo"),
+        action,
+        message_at(start + DAY + 3600 * 4, "bye"),
+    ];
+    let stats = ConversationStats::from_messages(&messages, DayBoundary::Utc);
+    assert_eq!(4, stats.total.messages);
+    assert_eq!(6, stats.total.words);
+    assert_eq!(8 + 5 + 11 + 3, stats.total.characters);
+    assert_eq!(Some(27.0 / 4.0), stats.average_message_length());
+    assert_eq!(3, stats.senders["Someone"].messages);
+    assert_eq!(Some(&1), stats.senders["Other"].types.get(&FChatMessageKind::Action));
+    assert_eq!(Some(&3), stats.total.types.get(&FChatMessageKind::Message));
+    assert_eq!(Some(messages[0].datetime), stats.first_activity);
+    assert_eq!(Some(messages[3].datetime), stats.last_activity);
+    let first_day = messages[0].datetime.date();
+    assert_eq!(vec![(first_day, 3), (first_day.succ_opt().unwrap(), 1)], stats.busiest_days(5));
+    assert_eq!(vec![(4, 3)], stats.busiest_hours(1));
+
+    let shifted = ConversationStats::from_messages(&messages, DayBoundary::Offset(FixedOffset::west_opt(5 * 3600).unwrap()));
+    assert_eq!(vec![(23, 3), (0, 1)], shifted.busiest_hours(24));
+    assert_eq!(first_day.pred_opt().unwrap(), shifted.busiest_days(1)[0].0);
+
+    let reader = FChatMessageReader::new(Cursor::new(TEST_CONTENTS));
+    let from_file = ConversationStats::from_reader(reader, DayBoundary::Utc)?;
+    assert_eq!(from_file.total.messages, from_file.days.values().sum::<u64>());
+    assert_eq!(from_file.total.messages, from_file.senders.values().map(|sender| sender.messages).sum::<u64>());
+    Ok(())
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn conversation_stats_serialise() -> Result<(), BoxedError> {
+    let stats = ConversationStats::from_messages(&[message_at(1_600_000_000, "hi there")], DayBoundary::Utc);
+    let json = serde_json::to_string(&stats)?;
+    assert!(json.contains("\"message\":1"));
+    let back: ConversationStats = serde_json::from_str(&json)?;
+    assert_eq!(stats, back);
+    Ok(())
+}
+
+#[test]
+fn activity_calendar_from_index() -> Result<(), BoxedError> {
+    let day = |n: u64| NaiveDate::from_ymd_opt(2020, 1, 1).unwrap() + chrono::Days::new(n);
+    let mut index = FChatIndex::new(String::from("Someone"));
+    for (n, offset) in [(0, 0), (1, 100), (2, 150), (5, 400), (9, 450), (10, 700)] {
+        index.offsets.push(FChatIndexOffset { date: day(n), offset });
+    }
+    let calendar = ActivityCalendar::from_index(&index, 1000);
+    assert_eq!(vec![100, 50, 250, 50, 250, 300], calendar.days.iter().map(|day| day.bytes).collect::<Vec<_>>());
+    assert_eq!(1000, calendar.total_bytes());
+    assert_eq!(Some(50), calendar.day(day(5)).map(|day| day.bytes));
+    assert!(calendar.day(day(4)).is_none());
+    assert_eq!(3, calendar.streaks().len());
+    assert_eq!(Some(DateSpan { first: day(0), last: day(2) }), calendar.longest_streak());
+    assert_eq!(vec![DateSpan { first: day(3), last: day(4) }, DateSpan { first: day(6), last: day(8) }], calendar.gaps());
+    assert_eq!(Some(3), calendar.longest_gap().map(|gap| gap.days()));
+
+    let dir = create_dir()?;
+    create_test_file(&dir, "log", TEST_CONTENTS)?;
+    create_test_file(&dir, "log.idx", TEST_INDEX)?;
+    let calendar = ActivityCalendar::from_files(dir.path().join("log"), dir.path().join("log.idx"))?;
+    assert_eq!(TEST_CONTENTS.len() as u64 - calendar.days[0].offset, calendar.total_bytes());
+    let err = ActivityCalendar::from_files(dir.path().join("missing"), dir.path().join("log.idx")).unwrap_err();
+    assert_eq!(Some(dir.path().join("missing").as_path()), err.path());
+    dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn sessions_split_on_idle_gaps() -> Result<(), BoxedError> {
+    let start = 1_600_000_000;
+    let mut reply = message_at(start + 600, "hello back");
+    reply.sender = String::from("Other");
+    let mut still_here = message_at(start + 600 + 3600, "still here");
+    still_here.sender = String::from("other");
+    let messages = vec![
+        message_at(start, "hello"),
+        reply,
+        still_here,
+        message_at(start + 600 + 3600 + 3601, "later"),
+    ];
+    let mut log = Cursor::new(Vec::new());
+    for message in &messages {
+        message.write_to_buf(&mut log)?;
+    }
+    let log_len = log.get_ref().len() as u64;
+    let mut cursor = FChatMessageCursor::new(&mut log)?;
+    let sessions = SessionDetector::sessions(&mut cursor, TimeDelta::hours(1))?;
+    assert_eq!(2, sessions.len());
+    assert_eq!(3, sessions[0].messages);
+    // However their name was capitalised, a participant is counted once.
+    assert_eq!(vec![(&String::from("other"), &2), (&String::from("someone"), &1)], sessions[0].participants.iter().collect::<Vec<_>>());
+    assert_eq!(messages[0].datetime, sessions[0].first_activity);
+    assert_eq!(TimeDelta::seconds(4200), sessions[0].duration());
+    assert_eq!(0, sessions[0].offsets.start);
+    assert_eq!(sessions[0].offsets.end, sessions[1].offsets.start);
+    assert_eq!(log_len, sessions[1].offsets.end);
+    cursor.seek_to(sessions[1].offsets.start)?;
+    assert_eq!("later", cursor.next_message().unwrap()?.1.body.text());
+    Ok(())
+}
+
+#[test]
+fn manuscript_from_scene() -> Result<(), BoxedError> {
+    let start = 1_600_000_000;
+    let mut action = message_at(start + 10, "draws a [b]sword[/b] [eicon]blade[/eicon]");
+    action.sender = String::from("Other");
+    action.body = FChatMessageType::Action(String::from(action.body.text()));
+    let mut roll = message_at(start + 20, "rolls 1d20");
+    roll.body = FChatMessageType::Roll(String::from("rolls 1d20"));
+    let mut bystander = message_at(start + 30, "hi");
+    bystander.sender = String::from("Bystander");
+    let messages = [
+        message_at(start - 10, "too early"),
+        message_at(start, "See [url=https://f-list.net]this[/url], 2 * 3 <b>"),
+        action,
+        roll,
+        bystander,
+        message_at(start + 40, "[i]unclosed and [/b] stray"),
+        message_at(start + 100, "too late"),
+    ];
+    let options = ManuscriptOptions {
+        format: ManuscriptFormat::Markdown,
+        from: Some(messages[1].utc_datetime()),
+        until: Some(messages[6].utc_datetime()),
+        participants: vec![String::from("someone"), String::from("OTHER")],
+    };
+    let mut markdown = Vec::new();
+    let written = write_manuscript(messages.iter().cloned().map(Ok), &options, &mut markdown)?;
+    assert_eq!(3, written);
+    assert_eq!(
+        "**Someone:** See [this](https://f-list.net), 2 \\* 3 \\<b\\>\n\n\
+         *Other* draws a **sword** \n\n\
+         **Someone:** *unclosed and \\[/b\\] stray*\n\n",
+        String::from_utf8(markdown)?
+    );
+
+    let mut html = Vec::new();
+    let options = ManuscriptOptions { format: ManuscriptFormat::Html, ..options };
+    write_manuscript(messages.iter().cloned().map(Ok), &options, &mut html)?;
+    let html = String::from_utf8(html)?;
+    assert!(html.starts_with("<p class=\"message\"><strong>Someone:</strong> See <a href=\"https://f-list.net\">this</a>, 2 * 3 &lt;b&gt;</p>\n"));
+    assert!(html.contains("<p class=\"action\"><em>Other</em> draws a <strong>sword</strong> </p>"));
+    assert_eq!("[b]not bold[/b] &lt;3", bbcode_to_html("[noparse][b]not bold[/b][/noparse] <3"));
+    assert_eq!("javascript:alert(1)", bbcode_to_html("[url]javascript:alert(1)[/url]"));
+    Ok(())
+}
+
+#[test]
+fn anonymise_log_and_idx() -> Result<(), BoxedError> {
+    let start = 1_600_000_000;
+    let mut alice = message_at(start, "Hi [user]Bob Smith[/user], mail me at alice@example.com");
+    alice.sender = String::from("Alice");
+    let mut bob = message_at(start + 86400, "hey ALICE, where's bob smith?");
+    bob.sender = String::from("Bob Smith");
+    bob.body = FChatMessageType::Action(String::from(bob.body.text()));
+
+    let dir = create_dir()?;
+    let log_fd = create_test_file(&dir, "log", &[])?;
+    let idx_fd = create_test_file(&dir, "log.idx", &[])?;
+    let mut writer = FChatWriter::new(&log_fd, &idx_fd, String::from("Bob Smith"))?;
+    writer.write_message(alice)?;
+    writer.write_message(bob)?;
+
+    let mut anonymiser = FChatAnonymiser::new().with_redaction(Regex::new(r"\S+@\S+")?);
+    let written = anonymiser.anonymise_files(
+        dir.path().join("log"), dir.path().join("log.idx"),
+        dir.path().join("out"), dir.path().join("out.idx"),
+    )?;
+    assert_eq!(2, written);
+    assert_eq!(Some(&String::from("Person 1")), anonymiser.pseudonyms().get("bob smith"));
+
+    let messages: Vec<FChatMessage> = FChatMessageReader::from_path(dir.path().join("out"))?.collect::<Result<_, _>>()?;
+    assert_eq!("Person 2", messages[0].sender);
+    assert_eq!("Hi [user]Person 1[/user], mail me at [redacted]", messages[0].body.text());
+    assert_eq!("Person 1", messages[1].sender);
+    assert_eq!("hey Person 2, where's Person 1?", messages[1].body.text());
+    let mut out_idx = BufReader::new(File::open(dir.path().join("out.idx"))?);
+    let index = FChatIndex::from_buf(&mut out_idx)?;
+    assert_eq!("Person 1", index.name);
+    assert_eq!(2, index.offsets.len());
+    assert!(index.matches_log(&mut File::open(dir.path().join("out"))?, DayBoundary::Utc)?);
+
+    // A channel keeps its name, which doesn't get taken for a character's in the text, and bad UTF-8 gets through.
+    let mut invalid = TEST_CONTENTS.to_vec();
+    invalid[12] = 0xff;
+    let mut frontpage = message_at(start, "welcome to the frontpage");
+    frontpage.sender = String::from("Alice");
+    invalid.extend(log_of(vec![frontpage])?);
+    std::fs::write(dir.path().join("#frontpage"), &invalid)?;
+    let mut idx = Vec::new();
+    FChatIndex::new(String::from("Frontpage")).write_header_to_buf(&mut idx)?;
+    std::fs::write(dir.path().join("#frontpage.idx"), &idx)?;
+    let written = FChatAnonymiser::new().anonymise_files(
+        dir.path().join("#frontpage"), dir.path().join("#frontpage.idx"),
+        dir.path().join("out"), dir.path().join("out.idx"),
+    )?;
+    assert_eq!(3, written);
+    let messages: Vec<FChatMessage> = FChatMessageReader::from_path(dir.path().join("out"))?.collect::<Result<_, _>>()?;
+    assert_eq!("\u{FFFD}ello World!", messages[0].body.text());
+    assert_eq!("welcome to the frontpage", messages[2].body.text());
+    assert_eq!("Frontpage", FChatIndex::from_buf(&mut File::open(dir.path().join("out.idx"))?)?.name);
+    dir.close()?;
+    Ok(())
+}
+
+/// Stores blocks as they are, for containers in tests that don't need a real codec.
+struct PlainCodec;
+
+impl BlockCodec for PlainCodec {
+    fn seal(&self, _context: u64, data: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(data.to_vec())
+    }
+
+    fn open(&self, _context: u64, data: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(data.to_vec())
+    }
+}
+
+#[test]
+fn container_commit_cut_short() -> Result<(), BoxedError> {
+    let dir = create_dir()?;
+    let path = dir.path().join("carlen white.container");
+    let open = || -> Result<FChatContainer<PlainCodec>, BoxedError> {
+        Ok(FChatContainer::open(OpenOptions::new().read(true).write(true).open(&path)?, PlainCodec)?)
+    };
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path)?;
+    let mut container = FChatContainer::create(file, b"PLAIN", PlainCodec, String::from("Carlen White"))?;
+    container.import(TEST_CONTENTS, TEST_INDEX)?;
+    let first = std::fs::read(&path)?;
+    let last = FChatMessageReader::new(TEST_CONTENTS).last().unwrap()?;
+    let append = |container: &mut FChatContainer<PlainCodec>| -> Result<(), BoxedError> {
+        let (log, idx) = container.streams();
+        FChatWriter::from_idx(log, idx)?.write_message(message_at(last.datetime.and_utc().timestamp() + 86400, "appended"))?;
+        container.commit()?;
+        Ok(())
+    };
+    append(&mut container)?;
+    drop(container);
+    let second = std::fs::read(&path)?;
+    assert!(second.starts_with(&first));
+
+    // However much of the second commit made it to disk, the container opens as the first commit left it.
+    for len in first.len()..second.len() {
+        std::fs::write(&path, &second[..len])?;
+        let mut container = open()?;
+        assert_eq!(TEST_CONTENTS.len() as u64, container.log().len());
+        assert_eq!(TEST_INDEX, container.streams().1.get_ref().as_slice());
+    }
+    let mut container = open()?;
+    append(&mut container)?;
+    drop(container);
+    assert_eq!(second, std::fs::read(&path)?);
+    assert_eq!("appended", FChatMessageReaderReversed::new(open()?.log())?.next().unwrap()?.body.text());
+
+    // A table pointing a block past the end of the file is refused rather than trusted.
+    let mut damaged = second.clone();
+    let footer = damaged.len() - 24;
+    let table_offset = (&damaged[footer..]).read_u64::<byteorder::LittleEndian>()? as usize;
+    let idx_len = (&damaged[table_offset + 8..]).read_u64::<byteorder::LittleEndian>()? as usize;
+    let first_block = table_offset + 16 + idx_len + 8;
+    damaged[first_block + 24..first_block + 32].copy_from_slice(&(u64::MAX / 2).to_le_bytes());
+    std::fs::write(&path, &damaged)?;
+    assert!(matches!(open().err().unwrap().downcast_ref::<Error>().unwrap().root(), Error::InvalidContainerError(_)));
+    dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn container_many_small_commits() -> Result<(), BoxedError> {
+    let dir = create_dir()?;
+    let path = dir.path().join("someone.container");
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path)?;
+    let mut container = FChatContainer::create(file, b"PLAIN", PlainCodec, String::from("Someone"))?;
+    let mut messages = Vec::new();
+    for day in 0..5 {
+        for n in 0..100 {
+            let body = format!("day {} message {}", day, n);
+            let (log, idx) = container.streams();
+            FChatWriter::from_idx(log, idx)?.write_message(message_at(DAY_START + day * DAY + n * 60, &body))?;
+            container.commit()?;
+            messages.push(body);
+        }
+    }
+    // Each day ends up in one block, and the file stays within a small multiple of what it holds.
+    assert_eq!(5, container.log().blocks().len());
+    let dates: Vec<NaiveDate> = container.block_dates()?.into_iter().map(|(date, _)| date).collect();
+    assert_eq!(container.index()?.offsets.iter().map(|offset| offset.date).collect::<Vec<_>>(), dates);
+    let held = container.log().len() + container.streams().1.get_ref().len() as u64;
+    assert!(std::fs::metadata(&path)?.len() < 3 * held);
+    drop(container);
+
+    let mut container = FChatContainer::open(OpenOptions::new().read(true).write(true).open(&path)?, PlainCodec)?;
+    let read: Vec<FChatMessage> = FChatMessageReader::new(container.log()).collect::<Result<_, _>>()?;
+    assert_eq!(messages, read.iter().map(|message| message.body.text()).collect::<Vec<_>>());
+    dir.close()?;
+    Ok(())
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn encrypted_container_round_trip() -> Result<(), BoxedError> {
+    use fchat3_log_lib::encrypted::{FChatEncryptedLog, FChatKeyDerivation};
+    let derivation = FChatKeyDerivation { memory_kib: 64, iterations: 1, parallelism: 1 };
+    let dir = create_dir()?;
+    let path = dir.path().join("carlen white.enc");
+    let mut container = FChatEncryptedLog::create_encrypted(&path, b"hunter2", String::from("Carlen White"), derivation)?;
+    container.import(TEST_CONTENTS, TEST_INDEX)?;
+    drop(container);
+    let raw = std::fs::read(&path)?;
+    assert!(!raw.windows(12).any(|window| window == b"Carlen White"));
+
+    let mut container = FChatEncryptedLog::open_encrypted(&path, b"hunter2")?;
+    let index = container.index()?;
+    assert_eq!("Carlen White", index.name);
+    assert_eq!(index.offsets.len(), container.log().blocks().len());
+    let plain: Vec<FChatMessage> = FChatMessageReader::new(TEST_CONTENTS).collect::<Result<_, _>>()?;
+    let decrypted: Vec<FChatMessage> = FChatMessageReader::new(container.log()).collect::<Result<_, _>>()?;
+    assert_eq!(plain.len(), decrypted.len());
+    let day = &index.offsets[index.offsets.len() / 2];
+    let mut cursor = FChatMessageCursor::from_index_offset(container.log(), day)?;
+    let (offset, message) = cursor.next_message().unwrap()?;
+    assert_eq!(day.offset, offset);
+    assert_eq!(day.date, message.datetime.date());
+    drop(cursor);
+
+    let (log, idx) = container.streams();
+    let mut writer = FChatWriter::from_idx(log, idx)?;
+    let next = plain.last().unwrap().datetime.and_utc().timestamp() + 2 * 86400;
+    writer.write_message(message_at(next, "appended"))?;
+    drop(writer);
+    container.commit()?;
+    drop(container);
+
+    let mut container = FChatEncryptedLog::open_encrypted(&path, b"hunter2")?;
+    assert_eq!(index.offsets.len() + 1, container.index()?.offsets.len());
+    let last = FChatMessageReaderReversed::new(container.log())?.next().unwrap()?;
+    assert_eq!("appended", last.body.text());
+    let mut exported = Vec::new();
+    container.export(&mut exported, std::io::sink())?;
+    assert!(exported.starts_with(TEST_CONTENTS));
+
+    let err = FChatEncryptedLog::open_encrypted(&path, b"wrong").err().unwrap();
+    assert!(matches!(err.root(), Error::InvalidContainerError(_)));
+
+    // A header asking for more memory than allowed is refused before any is used.
+    let mut greedy = std::fs::read(&path)?;
+    greedy[9..13].copy_from_slice(&u32::MAX.to_le_bytes());
+    std::fs::write(&path, &greedy)?;
+    let err = FChatEncryptedLog::open_encrypted(&path, b"hunter2").err().unwrap();
+    assert!(matches!(err.root(), Error::InvalidContainerError(_)));
+    dir.close()?;
+    Ok(())
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn compressed_archive_seeks_by_date() -> Result<(), BoxedError> {
+    use fchat3_log_lib::compressed::{CompressionCodec, FChatCompressedLog};
+    let start = DAY_START;
+    let mut log = Cursor::new(Vec::new());
+    let mut idx = Cursor::new(Vec::new());
+    let mut writer = FChatWriter::new(&mut log, &mut idx, String::from("Someone"))?;
+    for day in 0..20 {
+        for n in 0..10 {
+            writer.write_message(message_at(start + day * DAY + n * 60, &format!("day {} message {} with some padding", day, n)))?;
+        }
+    }
+    drop(writer);
+
+    let dir = create_dir()?;
+    let path = dir.path().join("someone.zst");
+    let codec = CompressionCodec { block_size: 1024, ..CompressionCodec::default() };
+    let mut archive = FChatCompressedLog::create_compressed(&path, String::from("Someone"), codec)?;
+    archive.import(log.get_ref().as_slice(), idx.get_ref().as_slice())?;
+    drop(archive);
+    assert!(std::fs::metadata(&path)?.len() < log.get_ref().len() as u64);
+
+    let mut archive = FChatCompressedLog::open_compressed(&path, CompressionCodec::default())?;
+    let index = archive.index()?;
+    assert_eq!(20, index.offsets.len());
+    let blocks = archive.block_dates()?;
+    assert!(blocks.len() > 1 && blocks.len() < index.offsets.len());
+    assert_eq!(archive.log().blocks(), blocks.iter().map(|(_, block)| *block).collect::<Vec<_>>().as_slice());
+    assert!(blocks.iter().all(|(date, block)| {
+        index.offsets.iter().take_while(|offset| offset.offset <= block.log_offset).last().unwrap().date == *date
+    }));
+
+    let day = &index.offsets[7];
+    let mut cursor = archive.cursor_at_date(day.date)?.unwrap();
+    let (offset, message) = cursor.next_message().unwrap()?;
+    assert_eq!(day.offset, offset);
+    assert_eq!("day 7 message 0 with some padding", message.body.text());
+    drop(cursor);
+    assert!(archive.cursor_at_date(index.offsets[0].date.pred_opt().unwrap())?.is_none());
+    let last_of_day = FChatMessageReaderReversed::from_end_of_day(archive.log(), &index, day.date)?.next().unwrap()?;
+    assert_eq!("day 7 message 9 with some padding", last_of_day.body.text());
+    assert_eq!(200, FChatMessageReader::new(archive.log()).collect::<Result<Vec<_>, _>>()?.len());
+
+    std::fs::write(dir.path().join("plain"), log.get_ref())?;
+    assert!(FChatCompressedLog::open_compressed(dir.path().join("plain"), CompressionCodec::default()).is_err());
+    dir.close()?;
+    Ok(())
+}
+
+#[cfg(feature = "archives")]
+#[test]
+fn read_logs_from_archives() -> Result<(), BoxedError> {
+    use fchat3_log_lib::archive::{read_compressed_log, FChatArchive};
+    let expected = FChatMessageReader::new(TEST_CONTENTS).count();
+    let files: [(&str, &[u8]); 4] = [
+        ("fchat/Carlen White/logs/carlen white", TEST_CONTENTS),
+        ("fchat/Carlen White/logs/carlen white.idx", TEST_INDEX),
+        ("fchat/Carlen White/settings", b"{}"),
+        ("old/loose", TEST_CONTENTS),
+    ];
+    let dir = create_dir()?;
+
+    let tar_path = dir.path().join("backup.tar.gz");
+    let gzip = flate2::write::GzEncoder::new(File::create(&tar_path)?, flate2::Compression::default());
+    let mut tar = tar::Builder::new(gzip);
+    for (name, contents) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, name, contents)?;
+    }
+    tar.into_inner()?.finish()?;
+
+    let zip_path = dir.path().join("backup.zip");
+    let mut zip = zip::ZipWriter::new(File::create(&zip_path)?);
+    for (name, contents) in files {
+        zip.start_file(name, zip::write::SimpleFileOptions::default())?;
+        zip.write_all(contents)?;
+    }
+    zip.finish()?;
+
+    // Zips made on Windows can have `\` between directories.
+    let windows_path = dir.path().join("windows.zip");
+    let mut zip = zip::ZipWriter::new(File::create(&windows_path)?);
+    for (name, contents) in files {
+        zip.start_file(name.replace('/', "\\"), zip::write::SimpleFileOptions::default())?;
+        zip.write_all(contents)?;
+    }
+    zip.finish()?;
+
+    for path in [&tar_path, &zip_path, &windows_path] {
+        let archive = FChatArchive::open(path)?;
+        assert_eq!(1, archive.conversations().len());
+        let conversation = &archive.conversations()[0];
+        assert_eq!("carlen white", conversation.file_name());
+        assert_eq!(Some("Carlen White"), conversation.character());
+        assert_eq!(TEST_CONTENTS.len() as u64, conversation.size);
+        assert_eq!("Carlen White", archive.read_index(conversation)?.unwrap().name);
+        assert_eq!(expected, archive.read_log(conversation)?.collect::<Result<Vec<_>, _>>()?.len());
+        let mut visited = 0;
+        archive.for_each_log(|_, reader| {
+            assert_eq!(expected, reader.collect::<Result<Vec<_>, _>>()?.len());
+            visited += 1;
+            Ok(())
+        })?;
+        assert_eq!(1, visited);
+        assert!(matches!(archive.read_file("missing").unwrap_err().root(), Error::InvalidArchiveError(_)));
+    }
+    let damaged_path = dir.path().join("damaged.zip");
+    std::fs::write(&damaged_path, b"PK\x03\x04 but not a zip")?;
+    assert!(matches!(FChatArchive::open(&damaged_path).err().unwrap().root(), Error::InvalidArchiveError(_)));
+
+    let zstd_path = dir.path().join("carlen white.zst");
+    std::fs::write(&zstd_path, zstd::encode_all(TEST_CONTENTS, 0)?)?;
+    assert_eq!(expected, read_compressed_log(&zstd_path)?.collect::<Result<Vec<_>, _>>()?.len());
+    let plain_path = dir.path().join("carlen white");
+    std::fs::write(&plain_path, TEST_CONTENTS)?;
+    assert_eq!(expected, read_compressed_log(&plain_path)?.count());
+    dir.close()?;
+    Ok(())
+}
+
+#[cfg(feature = "backup")]
+#[test]
+fn backup_and_restore_profile() -> Result<(), BoxedError> {
+    use fchat3_log_lib::archive::FChatArchive;
+    use fchat3_log_lib::backup::{backup_profile, read_manifest, restore_bundle};
+    use fchat3_log_lib::profile::FChatProfile;
+    let start = DAY_START;
+    let dir = create_dir()?;
+    let profile = FChatProfile::new(dir.path().join("Carlen White"));
+    std::fs::create_dir_all(profile.logs_dir())?;
+    for (file_name, count) in [("alice", 3), ("#frontpage", 5)] {
+        let conversation = profile.conversation(file_name);
+        let mut writer = FChatWriter::new(File::create(&conversation.log_path)?, File::create(&conversation.idx_path)?, file_name.to_string())?;
+        for n in 0..count {
+            writer.write_message(message_at(start + n * DAY, "same text"))?;
+        }
+    }
+
+    let bundle_path = dir.path().join("backup.tar.gz");
+    let manifest = backup_profile(&profile, &bundle_path)?;
+    assert_eq!(manifest, read_manifest(&bundle_path)?);
+    assert_eq!("Carlen White", manifest.character);
+    assert_eq!(2, manifest.conversations.len());
+    let frontpage = &manifest.conversations[0];
+    assert_eq!(("#frontpage", 5), (frontpage.name.as_str(), frontpage.messages));
+    assert_eq!(DateTime::from_timestamp(start + 4 * DAY, 0).map(|datetime| datetime.naive_utc()), frontpage.last_message);
+
+    // Lose one conversation entirely, and the start of another that has since moved on.
+    std::fs::remove_file(profile.conversation("alice").log_path)?;
+    std::fs::remove_file(profile.conversation("alice").idx_path)?;
+    let frontpage = profile.conversation("#frontpage");
+    let mut writer = FChatWriter::new(File::create(&frontpage.log_path)?, File::create(&frontpage.idx_path)?, String::from("#frontpage"))?;
+    for n in 3..7 {
+        writer.write_message(message_at(start + n * DAY, "same text"))?;
+    }
+    drop(writer);
+
+    let report = restore_bundle(&bundle_path, &profile)?;
+    assert_eq!((1, 1), (report.conversations_added, report.conversations_merged));
+    assert_eq!((3 + 3, 2), (report.messages_added, report.messages_skipped));
+    assert_eq!(3, profile.conversation("alice").reader()?.count());
+    let restored: Vec<FChatMessage> = frontpage.reader()?.collect::<Result<_, _>>()?;
+    assert_eq!(7, restored.len());
+    assert!(restored.windows(2).all(|pair| pair[0].datetime < pair[1].datetime));
+    let index = frontpage.index()?;
+    assert_eq!(7, index.offsets.len());
+    assert!(index.matches_log(&mut File::open(&frontpage.log_path)?, DayBoundary::Utc)?);
+    assert_eq!(0, restore_bundle(&bundle_path, &profile)?.messages_added);
+
+    // A bundle whose log doesn't match the manifest is refused without touching the profile.
+    let tampered_path = dir.path().join("tampered.tar");
+    let mut tar = tar::Builder::new(File::create(&tampered_path)?);
+    let manifest_tsv = FChatArchive::open(&bundle_path)?.read_file("manifest.tsv")?;
+    for (name, contents) in [("manifest.tsv", manifest_tsv.as_slice()), ("logs/alice", TEST_CONTENTS)] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_cksum();
+        tar.append_data(&mut header, name, contents)?;
+    }
+    tar.finish()?;
+    let err = restore_bundle(&tampered_path, &profile).unwrap_err();
+    assert!(matches!(err.root(), Error::InvalidArchiveError(_)));
+    assert_eq!(7, frontpage.reader()?.count());
+
+    // Neither a manifest nor the archive itself can name a file outside the logs directory.
+    let escaping_path = dir.path().join("escaping.tar");
+    let mut tar = tar::Builder::new(File::create(&escaping_path)?);
+    let escaping_tsv = String::from_utf8(manifest_tsv)?.replace("conversation\talice\t", "conversation\t../escaped\t");
+    let mut header = tar::Header::new_gnu();
+    header.set_size(escaping_tsv.len() as u64);
+    header.set_cksum();
+    tar.append_data(&mut header, "manifest.tsv", escaping_tsv.as_bytes())?;
+    tar.finish()?;
+    let err = restore_bundle(&escaping_path, &profile).unwrap_err();
+    assert!(matches!(err.root(), Error::InvalidArchiveError(_)));
+    assert!(err.to_string().contains("\"../escaped\" in the manifest isn't a plain file name"));
+    let mut tar = tar::Builder::new(File::create(&escaping_path)?);
+    let mut header = tar::Header::new_gnu();
+    header.as_old_mut().name[..15].copy_from_slice(b"logs/../escaped");
+    header.set_size(TEST_CONTENTS.len() as u64);
+    header.set_cksum();
+    tar.append(&header, TEST_CONTENTS)?;
+    tar.finish()?;
+    assert!(matches!(FChatArchive::open(&escaping_path).err().unwrap().root(), Error::InvalidArchiveError(_)));
+    assert!(!dir.path().join("Carlen White").join("escaped").exists());
+
+    // A damaged log stops the backup instead of being counted short.
+    let mut damaged = TEST_CONTENTS.to_vec();
+    damaged[24] ^= 0xff;
+    std::fs::write(profile.conversation("damaged").log_path, &damaged)?;
+    let err = backup_profile(&profile, dir.path().join("damaged.tar.gz")).unwrap_err();
+    assert!(matches!(err.root(), Error::MessageLengthError(_)));
+    assert_eq!(Some(profile.conversation("damaged").log_path.as_path()), err.path());
+    let mut invalid = TEST_CONTENTS.to_vec();
+    invalid[12] = 0xff;
+    std::fs::write(profile.conversation("damaged").log_path, [&invalid, &[1, 2, 3][..]].concat())?;
+    let torn_path = dir.path().join("torn.tar.gz");
+    let manifest = backup_profile(&profile, &torn_path)?;
+    assert_eq!(2, manifest.conversations.iter().find(|entry| entry.file_name == "damaged").unwrap().messages);
+    assert_eq!(invalid, FChatArchive::open(&torn_path)?.read_file("logs/damaged")?);
+
+    // Whatever the backup took restores: copied as it is where the profile doesn't have the log, and merged where it
+    // has a copy of its own, even one that's torn. A restore that fails partway leaves nothing behind.
+    let fresh = FChatProfile::new(dir.path().join("Fresh"));
+    let report = restore_bundle(&torn_path, &fresh)?;
+    assert_eq!((3, 0, 3 + 7 + 2), (report.conversations_added, report.conversations_merged, report.messages_added));
+    assert_eq!(invalid, std::fs::read(fresh.conversation("damaged").log_path)?);
+    assert!(fresh.conversation("damaged").index()?.matches_log(&mut File::open(fresh.conversation("damaged").log_path)?, DayBoundary::Utc)?);
+    let damaged = profile.conversation("damaged");
+    std::fs::write(&damaged.log_path, [1, 2, 3])?;
+    let blocked = profile.logs_dir().join(".damaged.idx.restore");
+    std::fs::create_dir(&blocked)?;
+    assert!(restore_bundle(&torn_path, &profile).is_err());
+    assert!(!profile.logs_dir().join(".damaged.restore").exists());
+    std::fs::remove_dir(&blocked)?;
+    let report = restore_bundle(&torn_path, &profile)?;
+    assert_eq!((1, 2), (report.conversations_merged, report.messages_added));
+    let restored: Vec<FChatMessage> = damaged.reader()?.collect::<Result<_, _>>()?;
+    assert_eq!(2, restored.len());
+    assert!(restored[0].body.text().starts_with('\u{FFFD}'));
+    dir.close()?;
+    Ok(())
+}
+
+#[cfg(feature = "backup")]
+#[test]
+fn incremental_backups() -> Result<(), BoxedError> {
+    use fchat3_log_lib::archive::FChatArchive;
+    use fchat3_log_lib::backup::{backup_increment, read_increment, restore_increments, BackupCheckpoint};
+    use fchat3_log_lib::profile::FChatProfile;
+    let start = DAY_START;
+    let dir = create_dir()?;
+    let profile = FChatProfile::new(dir.path().join("Carlen White"));
+    std::fs::create_dir_all(profile.logs_dir())?;
+    let alice = profile.conversation("alice");
+    let append = |from: i64, to: i64| -> Result<(), BoxedError> {
+        let mut writer = FChatWriter::open(&alice.log_path, &alice.idx_path, String::from("Alice"))?;
+        for n in from..to {
+            writer.write_message(message_at(start + n * DAY / 2, "hello"))?;
+        }
+        Ok(())
+    };
+    append(0, 4)?;
+    std::fs::write(profile.conversation("bob").log_path, TEST_CONTENTS)?;
+
+    let base_path = dir.path().join("base.tar.gz");
+    let base = backup_increment(&profile, &BackupCheckpoint::new(), &base_path)?;
+    assert_eq!(base, read_increment(&base_path)?);
+    let base_len = std::fs::metadata(&alice.log_path)?.len();
+    let lens: Vec<(String, u64)> = base.checkpoint().into_iter().map(|(file_name, backed_up)| (file_name, backed_up.len)).collect();
+    assert_eq!(vec![(String::from("alice"), base_len), (String::from("bob"), TEST_CONTENTS.len() as u64)], lens);
+
+    // A record the client is half way through writing waits for the next increment.
+    append(4, 7)?;
+    let appended_len = std::fs::metadata(&alice.log_path)?.len();
+    OpenOptions::new().append(true).open(&alice.log_path)?.write_all(&[1, 2, 3])?;
+    let first_path = dir.path().join("first.tar.gz");
+    let first = backup_increment(&profile, &base.checkpoint(), &first_path)?;
+    assert_eq!((base_len, appended_len), (first.conversations[0].start, first.conversations[0].end));
+    assert_eq!(first.conversations[1].start, first.conversations[1].end);
+
+    File::options().write(true).open(&alice.log_path)?.set_len(appended_len)?;
+    append(7, 9)?;
+    let second_path = dir.path().join("second.tar.gz");
+    let second = backup_increment(&profile, &first.checkpoint(), &second_path)?;
+
+    let restored = FChatProfile::new(dir.path().join("Restored"));
+    let checkpoint = restore_increments(&[&base_path, &first_path, &second_path], &restored)?;
+    assert_eq!(second.checkpoint(), checkpoint);
+    assert_eq!(std::fs::read(&alice.log_path)?, std::fs::read(restored.conversation("alice").log_path)?);
+    assert_eq!(TEST_CONTENTS, std::fs::read(restored.conversation("bob").log_path)?.as_slice());
+    let index = restored.conversation("alice").index()?;
+    assert_eq!(("Alice", 5), (index.name.as_str(), index.offsets.len()));
+    assert!(index.matches_log(&mut File::open(restored.conversation("alice").log_path)?, DayBoundary::Utc)?);
+
+    // Leaving an increment out, or backing up a log that was rewritten, is refused.
+    let gap = FChatProfile::new(dir.path().join("Gap"));
+    let err = restore_increments(&[&base_path, &second_path], &gap).unwrap_err();
+    assert!(matches!(err.root(), Error::InvalidArchiveError(_)));
+    assert_eq!(0, std::fs::read_dir(gap.logs_dir())?.count());
+
+    // An increment naming a file outside the logs directory is refused too.
+    let escaping_path = dir.path().join("escaping.tar");
+    let escaping_tsv = String::from_utf8(FChatArchive::open(&base_path)?.read_file("increment.tsv")?)?
+        .replace("conversation\talice\t", "conversation\t../escaped\t");
+    let mut tar = tar::Builder::new(File::create(&escaping_path)?);
+    let mut header = tar::Header::new_gnu();
+    header.set_size(escaping_tsv.len() as u64);
+    header.set_cksum();
+    tar.append_data(&mut header, "increment.tsv", escaping_tsv.as_bytes())?;
+    tar.finish()?;
+    let err = restore_increments(&[&escaping_path], &FChatProfile::new(dir.path().join("Escaping"))).unwrap_err();
+    assert!(err.to_string().contains("\"../escaped\" in the manifest isn't a plain file name"));
+    let backed_up = std::fs::read(restored.conversation("alice").log_path)?;
+    std::fs::write(&alice.log_path, &backed_up[1..])?;
+    let err = backup_increment(&profile, &second.checkpoint(), dir.path().join("third.tar.gz")).unwrap_err();
+    assert!(matches!(err.root(), Error::ConformanceError(_)));
+    // Even when the records still line up, the last one backed up has to be the same.
+    let mut edited = backed_up.clone();
+    let text_start = edited.len() - 2 - "hello".len();
+    edited[text_start] = b'j';
+    std::fs::write(&alice.log_path, &edited)?;
+    let err = backup_increment(&profile, &second.checkpoint(), dir.path().join("third.tar.gz")).unwrap_err();
+    assert!(matches!(err.root(), Error::ConformanceError(_)));
+    std::fs::write(&alice.log_path, &backed_up)?;
+    let third = backup_increment(&profile, &second.checkpoint(), dir.path().join("third.tar.gz"))?;
+    assert_eq!(second.checkpoint(), third.checkpoint());
+    dir.close()?;
+    Ok(())
+}
+
+fn log_of(messages: Vec<FChatMessage>) -> Result<Vec<u8>, BoxedError> {
+    let mut log = Cursor::new(Vec::new());
+    let mut writer = FChatWriter::new(&mut log, Cursor::new(Vec::new()), String::from("Someone"))?;
+    writer.write_batch(messages)?;
+    drop(writer);
+    Ok(log.into_inner())
+}
+
+#[test]
+fn diff_two_copies_of_a_log() -> Result<(), BoxedError> {
+    let from = |sender: &str, message: FChatMessage| FChatMessage { sender: String::from(sender), ..message };
+    let a = log_of(vec![
+        message_at(100, "hello"),
+        message_at(100, "hello"),
+        message_at(200, "only in a"),
+        from("Other", message_at(300, "same second")),
+        message_at(300, "typo'd"),
+        message_at(400, "the end"),
+    ])?;
+    let b = log_of(vec![
+        message_at(100, "hello"),
+        message_at(100, "hello"),
+        message_at(300, "typoed"),
+        from("Other", message_at(300, "same second")),
+        message_at(350, "only in b"),
+        message_at(400, "the end"),
+    ])?;
+    let (mut a_log, mut b_log) = (Cursor::new(a.as_slice()), Cursor::new(b.as_slice()));
+    let diff = LogDiff::from_cursors(&mut FChatMessageCursor::new(&mut a_log)?, &mut FChatMessageCursor::new(&mut b_log)?)?;
+    assert_eq!(4, diff.same);
+    assert_eq!(vec!["only in a"], diff.only_in_a().map(|message| message.text.as_str()).collect::<Vec<_>>());
+    assert_eq!(vec!["only in b"], diff.only_in_b().map(|message| message.text.as_str()).collect::<Vec<_>>());
+    let changed: Vec<_> = diff.changed().map(|(a, b)| (a.text.as_str(), b.text.as_str())).collect();
+    assert_eq!(vec![("typo'd", "typoed")], changed);
+    assert!(matches!(diff.differences[0], LogDifference::OnlyInA(_)));
+    let shown = diff.to_string();
+    assert!(shown.contains("< 1970-01-01 00:05:00 [message] Someone: typo'd\n> 1970-01-01 00:05:00 [message] Someone: typoed"));
+    assert!(shown.ends_with("4 the same, 1 only in A, 1 only in B, 1 changed"));
+
+    let dir = create_dir()?;
+    create_test_file(&dir, "a", &a)?;
+    create_test_file(&dir, "b", &a)?;
+    assert!(LogDiff::from_files(dir.path().join("a"), dir.path().join("b"))?.is_empty());
+    #[cfg(feature = "serde")]
+    {
+        let json = serde_json::to_string(&diff)?;
+        assert!(json.contains("\"difference\":\"changed\""));
+        assert_eq!(diff, serde_json::from_str(&json)?);
+    }
+    dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn dedup_repeated_records() -> Result<(), BoxedError> {
+    let dir = create_dir()?;
+    let (log_path, idx_path) = (dir.path().join("someone"), dir.path().join("someone.idx"));
+    let messages = vec![
+        message_at(DAY - 1, "hi"),
+        message_at(DAY - 1, "hi"),
+        message_at(DAY - 1, "hi"),
+        message_at(DAY, "a new day"),
+        message_at(DAY + 1, "in between"),
+        message_at(DAY + 3, "a new day"),
+        message_at(DAY + 60, "hi"),
+    ];
+    let mut writer = FChatWriter::new(File::create(&log_path)?, File::create(&idx_path)?, String::from("Someone Else"))?;
+    writer.write_batch(messages)?;
+    drop(writer);
+
+    let untouched = std::fs::read(&log_path)?;
+    let report = FChatDeduplicator::new(TimeDelta::seconds(0)).dedup_files(&log_path, &idx_path)?;
+    assert_eq!((5, 2), (report.messages_kept, report.messages_removed));
+    let before = std::fs::metadata(&log_path)?.len();
+    assert!(before < untouched.len() as u64);
+    let report = FChatDeduplicator::new(TimeDelta::seconds(5)).dedup_files(&log_path, &idx_path)?;
+    assert_eq!((4, 1), (report.messages_kept, report.messages_removed));
+    assert_eq!(before - report.bytes_removed, std::fs::metadata(&log_path)?.len());
+    let texts: Vec<String> = FChatMessageReader::from_path(&log_path)?.map(|message| message.map(|message| message.body.text().to_string())).collect::<Result<_, _>>()?;
+    assert_eq!(vec!["hi", "a new day", "in between", "hi"], texts);
+    let index = FChatIndex::from_buf(&mut File::open(&idx_path)?)?;
+    assert_eq!(("Someone Else", 2), (index.name.as_str(), index.offsets.len()));
+    assert!(index.matches_log(&mut File::open(&log_path)?, DayBoundary::Utc)?);
+
+    let deduped = std::fs::read(&log_path)?;
+    assert_eq!(0, FChatDeduplicator::new(TimeDelta::seconds(5)).dedup_files(&log_path, &idx_path)?.messages_removed);
+    assert_eq!(deduped, std::fs::read(&log_path)?);
+
+    // One deduplicator going through several logs treats each on its own.
+    let (other_log_path, other_idx_path) = (dir.path().join("someone else"), dir.path().join("someone else.idx"));
+    let mut writer = FChatWriter::new(File::create(&other_log_path)?, File::create(&other_idx_path)?, String::from("Someone"))?;
+    writer.write_message(message_at(DAY + 60, "hi"))?;
+    drop(writer);
+    let mut deduplicator = FChatDeduplicator::new(TimeDelta::seconds(5));
+    deduplicator.dedup_files(&log_path, &idx_path)?;
+    assert_eq!(0, deduplicator.dedup_files(&other_log_path, &other_idx_path)?.messages_removed);
+
+    // A log that can't be read is left as it was, without anything left next to it.
+    let mut damaged = deduped.clone();
+    damaged[deduped.len() - 1] ^= 0xff;
+    std::fs::write(&log_path, &damaged)?;
+    assert!(deduplicator.dedup_files(&log_path, &idx_path).is_err());
+    assert_eq!(damaged, std::fs::read(&log_path)?);
+    assert!(!dir.path().join(".someone.dedup").exists());
+    dir.close()?;
+    Ok(())
+}
+
+#[test]
+fn character_names() -> Result<(), BoxedError> {
+    for name in ["Carlen White", "a", "Some-Body_2", "TwentyCharactersLong"] {
+        assert!(validate_character_name(name).is_ok(), "{}", name);
+    }
+    for name in ["", " Leading", "Trailing ", "TwentyOneCharacters!!", "Twenty One Characters", "#frontpage", "ADH-\u{e9}"] {
+        let err = validate_character_name(name).unwrap_err();
+        assert!(matches!(err, Error::InvalidNameError(_)), "{}", name);
+    }
+    assert!(message_at(0, "hi").validate_sender().is_ok());
+    assert!(FChatIndex::new(String::from("#frontpage")).validate_name().is_err());
+    assert_eq!("carlen white", canonical_name("Carlen White"));
+    assert!(same_character("CARLEN white", "Carlen White"));
+    assert!(!same_character("Carlen", "Carlen White"));
+    let profile = fchat3_log_lib::profile::FChatProfile::new("Someone");
+    assert_eq!("carlen white", profile.private_conversation("Carlen White").file_name);
+
+    let from = |sender: &str, body: &str| FChatMessage { sender: String::from(sender), ..message_at(0, body) };
+    let log = log_of(vec![from("Alice", "one"), from("Bob", "two"), from("ALICE", "three"), from("Carol", "four")])?;
+    let texts = |messages: Vec<FChatMessage>| messages.into_iter().map(|message| message.body.text().to_string()).collect::<Vec<_>>();
+    let forwards: Vec<FChatMessage> = FChatMessageReader::new(log.as_slice()).with_sender("alice").collect::<Result<_, _>>()?;
+    assert_eq!(vec!["one", "three"], texts(forwards));
+    let backwards: Vec<FChatMessage> = FChatMessageReaderReversed::new(Cursor::new(log.as_slice()))?.with_sender("Alice").collect::<Result<_, _>>()?;
+    assert_eq!(vec!["three", "one"], texts(backwards));
+    let mut cursor = FChatMessageCursor::new(Cursor::new(log.as_slice()))?.with_sender("bob");
+    assert_eq!("two", cursor.next_message().unwrap()?.1.body.text());
+    assert!(cursor.next_message().is_none());
+    Ok(())
+}
+
+#[test]
+fn conversation_kinds() -> Result<(), BoxedError> {
+    use fchat3_log_lib::profile::{FChatConversationKind, FChatProfile};
+    let dir = create_dir()?;
+    let profile = FChatProfile::new(dir.path().join("Carlen White"));
+    std::fs::create_dir_all(profile.logs_dir())?;
+    for file_name in ["_", "#frontpage", "#ADH-1a2b3c4d", "someone else", "renamed.bak", "notes!"] {
+        std::fs::write(profile.conversation(file_name).log_path, TEST_CONTENTS)?;
+    }
+    let mut idx = File::create(profile.conversation("renamed.bak").idx_path)?;
+    FChatIndex::new(String::from("Someone Else")).write_header_to_buf(&mut idx)?;
+
+    let kinds: Vec<(String, FChatConversationKind)> = profile.conversations()?.into_iter()
+        .map(|conversation| (conversation.file_name.clone(), conversation.kind()))
+        .collect();
+    assert_eq!(vec![
+        (String::from("#ADH-1a2b3c4d"), FChatConversationKind::PrivateChannel),
+        (String::from("#frontpage"), FChatConversationKind::PublicChannel),
+        (String::from("_"), FChatConversationKind::Console),
+        (String::from("notes!"), FChatConversationKind::Unknown),
+        (String::from("renamed.bak"), FChatConversationKind::Private),
+        (String::from("someone else"), FChatConversationKind::Private),
+    ], kinds);
+    let private: Vec<String> = profile.conversations_of(FChatConversationKind::Private)?.into_iter().map(|conversation| conversation.file_name).collect();
+    assert_eq!(vec!["renamed.bak", "someone else"], private);
+    #[cfg(feature = "archives")]
+    {
+        let archived = fchat3_log_lib::archive::ArchivedConversation { log_path: String::from("Carlen White/logs/#adh-99"), idx_path: None, size: 0 };
+        assert_eq!(FChatConversationKind::PrivateChannel, archived.kind());
+    }
+    dir.close()?;
+    Ok(())
+}